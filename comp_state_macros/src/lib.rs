@@ -0,0 +1,142 @@
+//! Procedural macros for comp_state, re-exported by the comp_state crate.
+
+extern crate proc_macro;
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, GenericParam, ItemFn};
+
+/// Marks a function as a component.
+///
+/// This is equivalent to `#[topo::nested]`, and in addition the body is run
+/// through `comp_state::enter_component` so that the component is registered in the
+/// store's component tree under the function's name. In debug builds a component
+/// panics if a hook is called more than once from the same place in its body, for
+/// instance `use_state` inside a loop without a key.
+///
+/// Hooks are told apart by where they are called from, so custom hooks, i.e.
+/// functions that call hooks, must be `#[track_caller]` or `#[topo::nested]`.
+/// Otherwise calling the same custom hook twice is reported as a hook in a loop:
+///
+/// ```ignore
+/// #[track_caller]
+/// fn use_counter() -> StateAccess<i32> {
+///     use_state(|| 0)
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn component(attrs: TokenStream, input: TokenStream) -> TokenStream {
+    if !attrs.is_empty() {
+        return syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "#[component] does not take any arguments",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let mut input_fn = parse_macro_input!(input as ItemFn);
+    let name = input_fn.sig.ident.to_string();
+    let inner_block = input_fn.block;
+
+    // the same expansion as #[topo::nested], with full paths so that topo need not be in scope
+    input_fn.block = parse_quote! {{
+        ::comp_state::topo::call(|| ::comp_state::enter_component(#name, || #inner_block))
+    }};
+
+    quote!(#[track_caller] #input_fn).into()
+}
+
+/// Derives per-field accessors for a struct stored with `use_state`.
+///
+/// For a struct `Model` this generates a trait `ModelCompState`, implemented for
+/// `StateAccess<Model>`, with one method per named field returning a
+/// `comp_state::Lens` onto that field, i.e. `access.title().set(..)`.
+///
+/// Fields whose names clash with methods of StateAccess, such as `get` or `set`,
+/// are shadowed by those methods and need to be called through the trait.
+#[proc_macro_derive(CompState)]
+pub fn derive_comp_state(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return syn::Error::new_spanned(
+                    &input.ident,
+                    "CompState can only be derived for structs with named fields",
+                )
+                .to_compile_error()
+                .into()
+            }
+        },
+        _ => {
+            return syn::Error::new_spanned(
+                &input.ident,
+                "CompState can only be derived for structs",
+            )
+            .to_compile_error()
+            .into()
+        }
+    };
+
+    if let Some(lifetime) = input.generics.lifetimes().next() {
+        return syn::Error::new_spanned(
+            lifetime,
+            "CompState cannot be derived for structs with lifetimes, state must be 'static",
+        )
+        .to_compile_error()
+        .into();
+    }
+
+    let vis = &input.vis;
+    let name = &input.ident;
+    let trait_name = format_ident!("{}CompState", name);
+
+    // stored state has to be 'static so every type parameter gets that bound
+    let mut generics = input.generics.clone();
+    for param in &mut generics.params {
+        if let GenericParam::Type(type_param) = param {
+            type_param.bounds.push(parse_quote!('static));
+        }
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let signatures = fields
+        .iter()
+        .map(|field| {
+            let ident = &field.ident;
+            let ty = &field.ty;
+            quote! {
+                fn #ident(&self) -> ::comp_state::Lens<#name #ty_generics, #ty>
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let methods = fields.iter().zip(&signatures).map(|(field, signature)| {
+        let ident = &field.ident;
+        quote! {
+            #signature {
+                ::comp_state::Lens::new(*self, |s| &s.#ident, |s| &mut s.#ident)
+            }
+        }
+    });
+
+    let doc = format!(
+        "Field accessors for `StateAccess<{}>`, generated by `#[derive(CompState)]`.",
+        name
+    );
+
+    let expanded = quote! {
+        #[doc = #doc]
+        #vis trait #trait_name #impl_generics #where_clause {
+            #( #signatures; )*
+        }
+
+        impl #impl_generics #trait_name #ty_generics for ::comp_state::StateAccess<#name #ty_generics> #where_clause {
+            #( #methods )*
+        }
+    };
+
+    expanded.into()
+}
@@ -0,0 +1,94 @@
+use crate::state_functions::{read_atom, subscribe_to_atom, update_atom};
+use std::marker::PhantomData;
+
+/// App-wide state keyed by a type rather than by a topological context.
+///
+/// Atoms are never garbage collected. Usually declared with the `atom!` macro.
+pub trait Atom: 'static {
+    type Value: 'static;
+
+    /// the value of the atom before it is first set
+    fn init() -> Self::Value;
+}
+
+/// Declares one or more atoms.
+///
+/// # Examples
+///
+/// ```
+/// # use comp_state::{atom, use_atom};
+/// atom! {
+///     pub Theme: String = "dark".to_string();
+///     Counter: u32 = 0;
+/// }
+///
+/// let theme = use_atom::<Theme>();
+/// ```
+#[macro_export]
+macro_rules! atom {
+    ($($(#[$meta:meta])* $vis:vis $name:ident: $value:ty = $init:expr;)*) => {
+        $(
+            $(#[$meta])*
+            #[derive(Debug, Clone, Copy)]
+            $vis struct $name;
+
+            impl $crate::Atom for $name {
+                type Value = $value;
+
+                fn init() -> $value {
+                    $init
+                }
+            }
+        )*
+    };
+}
+
+/// Constructs an accessor for the atom A, initialising it on first use.
+///
+/// The calling context is recorded as reading the atom, so that it shows up in
+/// `take_dirty_ids` once the atom changes.
+pub fn use_atom<A: Atom>() -> AtomAccess<A> {
+    subscribe_to_atom::<A>();
+    AtomAccess {
+        _phantom_data: PhantomData,
+    }
+}
+
+pub struct AtomAccess<A> {
+    _phantom_data: PhantomData<A>,
+}
+
+impl<A> std::fmt::Debug for AtomAccess<A> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(AtomAccess {})", std::any::type_name::<A>())
+    }
+}
+
+impl<A> Copy for AtomAccess<A> {}
+impl<A> Clone for AtomAccess<A> {
+    fn clone(&self) -> AtomAccess<A> {
+        *self
+    }
+}
+
+impl<A: Atom> AtomAccess<A> {
+    pub fn get(&self) -> A::Value
+    where
+        A::Value: Clone,
+    {
+        read_atom::<A, _, _>(|value| value.clone())
+    }
+
+    pub fn get_with<R, F: FnOnce(&A::Value) -> R>(&self, func: F) -> R {
+        read_atom::<A, _, _>(func)
+    }
+
+    pub fn set(&self, value: A::Value) {
+        update_atom::<A, _>(|current| *current = value);
+    }
+
+    /// updates the atom in place, func must not read or update this atom itself
+    pub fn update<F: FnOnce(&mut A::Value)>(&self, func: F) {
+        update_atom::<A, _>(func);
+    }
+}
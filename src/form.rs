@@ -0,0 +1,429 @@
+use crate::state_access::StateAccess;
+use crate::state_functions::use_state;
+use std::collections::HashMap;
+use std::panic::Location;
+use std::rc::Rc;
+
+type Validator<M> = Rc<dyn Fn(&M) -> Result<(), String>>;
+
+/// Constructs a form for the model type M, starting from M::default().
+///
+/// Fields are declared by calling `field` on the returned FormControl every render.
+#[track_caller]
+pub fn use_form<M>() -> FormControl<M>
+where
+    M: Default + Clone + 'static,
+{
+    use_form_with(M::default)
+}
+
+/// Constructs a form whose initial model is produced by the passed closure.
+#[track_caller]
+pub fn use_form_with<M, F>(initial_model_fn: F) -> FormControl<M>
+where
+    M: Clone + 'static,
+    F: FnOnce() -> M,
+{
+    let form_access = use_state(|| Form::new(initial_model_fn()));
+
+    FormControl::new(form_access)
+}
+
+/// Bookkeeping for a single field of a form.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FieldState {
+    /// the value differs from the value in the initial model
+    pub dirty: bool,
+    /// the field has been touched (blurred) by the user, or the form was submitted
+    pub touched: bool,
+    /// errors from the synchronous validators
+    pub errors: Vec<String>,
+    /// errors reported by the last completed asynchronous validation
+    pub async_errors: Vec<String>,
+    /// an asynchronous validation is in flight
+    pub validating: bool,
+    async_generation: u32,
+}
+
+pub struct Form<M> {
+    pub initial: M,
+    pub model: M,
+    pub fields: HashMap<&'static str, FieldState>,
+    pub submit_count: u32,
+    validators: HashMap<&'static str, Vec<(&'static Location<'static>, Validator<M>)>>,
+}
+
+impl<M> Form<M>
+where
+    M: Clone + 'static,
+{
+    fn new(initial: M) -> Form<M> {
+        Form {
+            model: initial.clone(),
+            initial,
+            fields: HashMap::new(),
+            submit_count: 0,
+            validators: HashMap::new(),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.fields.values().all(|field| {
+            field.errors.is_empty() && field.async_errors.is_empty() && !field.validating
+        })
+    }
+}
+
+pub struct FormControl<M> {
+    form_access: StateAccess<Form<M>>,
+}
+
+impl<M> Copy for FormControl<M> {}
+impl<M> Clone for FormControl<M> {
+    fn clone(&self) -> FormControl<M> {
+        *self
+    }
+}
+
+impl<M> FormControl<M>
+where
+    M: Clone + 'static,
+{
+    fn new(form_access: StateAccess<Form<M>>) -> FormControl<M> {
+        FormControl { form_access }
+    }
+
+    /// Registers a field of the model and returns its accessor.
+    ///
+    /// The getters pick the field out of the model, for instance
+    /// `form.field("title", |m| &m.title, |m| &mut m.title)`.
+    #[track_caller]
+    pub fn field<F>(
+        &self,
+        name: &'static str,
+        getter: fn(&M) -> &F,
+        getter_mut: fn(&mut M) -> &mut F,
+    ) -> FieldControl<M, F>
+    where
+        F: Clone + PartialEq + 'static,
+    {
+        self.form_access.update(|form| {
+            form.fields.entry(name).or_default();
+        });
+        FieldControl {
+            form_access: self.form_access,
+            name,
+            getter,
+            getter_mut,
+        }
+    }
+
+    /// returns a clone of the current model
+    pub fn model(&self) -> M {
+        self.form_access.get_with(|form| form.model.clone())
+    }
+
+    pub fn field_names(&self) -> Vec<&'static str> {
+        self.form_access
+            .get_with(|form| form.fields.keys().copied().collect())
+    }
+
+    pub fn field_state(&self, name: &str) -> Option<FieldState> {
+        self.form_access
+            .get_with(|form| form.fields.get(name).cloned())
+    }
+
+    /// runs every synchronous validator and reports whether the form has no
+    /// errors and no pending asynchronous validations. The stored errors are left
+    /// as they are.
+    pub fn is_valid(&self) -> bool {
+        let no_errors = self
+            .run_validators()
+            .iter()
+            .all(|(_, errors)| errors.is_empty());
+        no_errors
+            && self.form_access.get_with(|form| {
+                form.fields
+                    .values()
+                    .all(|field| field.async_errors.is_empty() && !field.validating)
+            })
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.form_access
+            .get_with(|form| form.fields.values().any(|field| field.dirty))
+    }
+
+    pub fn is_touched(&self) -> bool {
+        self.form_access
+            .get_with(|form| form.fields.values().any(|field| field.touched))
+    }
+
+    pub fn submit_count(&self) -> u32 {
+        self.form_access.get_with(|form| form.submit_count)
+    }
+
+    /// Marks every field as touched and validates the form.
+    /// If the form is valid the handler is called with the model and true is returned.
+    #[track_caller]
+    pub fn submit<F: FnOnce(M)>(&self, handler: F) -> bool {
+        self.form_access.update(|form| {
+            form.submit_count += 1;
+            for field in form.fields.values_mut() {
+                field.touched = true;
+            }
+        });
+        self.validate_all();
+        let valid_model = self.form_access.get_with(|form| {
+            if form.is_valid() {
+                Some(form.model.clone())
+            } else {
+                None
+            }
+        });
+        match valid_model {
+            Some(model) => {
+                handler(model);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Runs the synchronous validators of every field against a clone of the model.
+    // They run while the form is in the store, so they can read the form themselves.
+    fn run_validators(&self) -> Vec<(&'static str, Vec<String>)> {
+        let (model, validators) = self.form_access.get_with(|form| {
+            let validators = form
+                .fields
+                .keys()
+                .map(|name| {
+                    let validators = form
+                        .validators
+                        .get(name)
+                        .map(|validators| {
+                            validators
+                                .iter()
+                                .map(|(_, validator)| validator.clone())
+                                .collect::<Vec<_>>()
+                        })
+                        .unwrap_or_default();
+                    (*name, validators)
+                })
+                .collect::<Vec<_>>();
+            (form.model.clone(), validators)
+        });
+        validators
+            .into_iter()
+            .map(|(name, validators)| {
+                let errors = validators
+                    .iter()
+                    .filter_map(|validator| validator(&model).err())
+                    .collect();
+                (name, errors)
+            })
+            .collect()
+    }
+
+    // Runs every synchronous validator and stores the errors.
+    #[track_caller]
+    fn validate_all(&self) {
+        let errors = self.run_validators();
+        self.form_access.update(|form| {
+            for (name, errors) in errors {
+                form.fields.entry(name).or_default().errors = errors;
+            }
+        });
+    }
+
+    /// Restores the initial model and clears all field flags and errors.
+    #[track_caller]
+    pub fn reset(&self) {
+        self.form_access.update(|form| {
+            form.model = form.initial.clone();
+            form.submit_count = 0;
+            for field in form.fields.values_mut() {
+                *field = FieldState {
+                    async_generation: field.async_generation.wrapping_add(1),
+                    ..FieldState::default()
+                };
+            }
+        });
+    }
+
+    /// Resets the form to a new initial model, for instance after a successful save.
+    #[track_caller]
+    pub fn reset_to(&self, initial: M) {
+        self.form_access.update(|form| form.initial = initial);
+        self.reset();
+    }
+}
+
+/// Accessor for a single field of a form, returned by `FormControl::field`.
+pub struct FieldControl<M, F> {
+    form_access: StateAccess<Form<M>>,
+    pub name: &'static str,
+    getter: fn(&M) -> &F,
+    getter_mut: fn(&mut M) -> &mut F,
+}
+
+impl<M, F> Copy for FieldControl<M, F> {}
+impl<M, F> Clone for FieldControl<M, F> {
+    fn clone(&self) -> FieldControl<M, F> {
+        *self
+    }
+}
+
+impl<M, F> FieldControl<M, F>
+where
+    M: Clone + 'static,
+    F: Clone + PartialEq + 'static,
+{
+    /// Adds a synchronous validator to the field.
+    ///
+    /// Validators are identified by where they are added, so calling this every
+    /// render replaces the validator rather than adding another one.
+    #[track_caller]
+    pub fn validate<V>(self, validator: V) -> Self
+    where
+        V: Fn(&F) -> Result<(), String> + 'static,
+    {
+        let getter = self.getter;
+        self.add_validator(
+            Location::caller(),
+            Rc::new(move |model| validator(getter(model))),
+        )
+    }
+
+    /// Adds a validator that is passed the whole model, for checks across fields
+    /// such as a password confirmation. Its errors are reported on this field.
+    #[track_caller]
+    pub fn validate_model<V>(self, validator: V) -> Self
+    where
+        V: Fn(&M) -> Result<(), String> + 'static,
+    {
+        self.add_validator(Location::caller(), Rc::new(validator))
+    }
+
+    #[track_caller]
+    fn add_validator(self, location: &'static Location<'static>, validator: Validator<M>) -> Self {
+        self.form_access.update(|form| {
+            let validators = form.validators.entry(self.name).or_default();
+            if let Some(existing) = validators.iter_mut().find(|(l, _)| *l == location) {
+                existing.1 = validator;
+            } else {
+                validators.push((location, validator));
+            }
+        });
+        self
+    }
+
+    pub fn get(&self) -> F {
+        self.get_with(|value| value.clone())
+    }
+
+    pub fn get_with<R, G: FnOnce(&F) -> R>(&self, func: G) -> R {
+        let getter = self.getter;
+        self.form_access.get_with(|form| func(getter(&form.model)))
+    }
+
+    /// Sets the field's value, updating its dirty flag and running the validators.
+    /// Any asynchronous validation in flight is discarded.
+    #[track_caller]
+    pub fn set(&self, value: F) {
+        self.update(|field_value| *field_value = value);
+    }
+
+    #[track_caller]
+    pub fn update<G: FnOnce(&mut F)>(&self, func: G) {
+        let (getter, getter_mut, name) = (self.getter, self.getter_mut, self.name);
+        self.form_access.update(|form| {
+            func(getter_mut(&mut form.model));
+            let dirty = getter(&form.model) != getter(&form.initial);
+            let field = form.fields.entry(name).or_default();
+            field.dirty = dirty;
+            field.async_errors = vec![];
+            field.validating = false;
+            field.async_generation = field.async_generation.wrapping_add(1);
+        });
+        // every field, a validator of another field may depend on this one
+        FormControl::new(self.form_access).validate_all();
+    }
+
+    /// Marks the field as touched, typically on blur.
+    #[track_caller]
+    pub fn touch(&self) {
+        self.with_state_mut(|field| field.touched = true);
+    }
+
+    pub fn state(&self) -> FieldState {
+        let name = self.name;
+        self.form_access
+            .get_with(|form| form.fields.get(name).cloned().unwrap_or_default())
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.state().dirty
+    }
+
+    pub fn is_touched(&self) -> bool {
+        self.state().touched
+    }
+
+    pub fn is_validating(&self) -> bool {
+        self.state().validating
+    }
+
+    pub fn is_valid(&self) -> bool {
+        let state = self.state();
+        state.errors.is_empty() && state.async_errors.is_empty() && !state.validating
+    }
+
+    /// All current errors of the field, synchronous first.
+    pub fn errors(&self) -> Vec<String> {
+        let state = self.state();
+        state.errors.into_iter().chain(state.async_errors).collect()
+    }
+
+    /// The errors that should be shown to the user, i.e. none until the field has
+    /// been touched or the form has been submitted.
+    pub fn visible_errors(&self) -> Vec<String> {
+        if self.is_touched() {
+            self.errors()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Marks an asynchronous validation as started and returns a token for it.
+    ///
+    /// Pass the token back to `finish_async_validation` when the validation
+    /// completes, results for a value that has since changed are ignored.
+    #[track_caller]
+    pub fn start_async_validation(&self) -> u32 {
+        let mut token = 0;
+        self.with_state_mut(|field| {
+            field.async_generation = field.async_generation.wrapping_add(1);
+            field.validating = true;
+            token = field.async_generation;
+        });
+        token
+    }
+
+    #[track_caller]
+    pub fn finish_async_validation(&self, token: u32, errors: Vec<String>) {
+        self.with_state_mut(|field| {
+            if field.async_generation == token {
+                field.validating = false;
+                field.async_errors = errors;
+            }
+        });
+    }
+
+    #[track_caller]
+    fn with_state_mut<G: FnOnce(&mut FieldState)>(&self, func: G) {
+        let name = self.name;
+        self.form_access
+            .update(|form| func(form.fields.entry(name).or_default()));
+    }
+}
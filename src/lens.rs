@@ -0,0 +1,68 @@
+use crate::state_access::StateAccess;
+
+/// Accessor for a single field of a state, created with `StateAccess::lens`.
+///
+/// A lens reads and writes only its field, so a child component can be handed a
+/// lens rather than an accessor to the whole parent state.
+pub struct Lens<P, F> {
+    pub access: StateAccess<P>,
+    getter: fn(&P) -> &F,
+    getter_mut: fn(&mut P) -> &mut F,
+}
+
+impl<P, F> std::fmt::Debug for Lens<P, F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Lens({:#?})", self.access.id)
+    }
+}
+
+impl<P, F> Copy for Lens<P, F> {}
+impl<P, F> Clone for Lens<P, F> {
+    fn clone(&self) -> Lens<P, F> {
+        *self
+    }
+}
+
+impl<P, F> Lens<P, F>
+where
+    P: 'static,
+    F: 'static,
+{
+    pub fn new(
+        access: StateAccess<P>,
+        getter: fn(&P) -> &F,
+        getter_mut: fn(&mut P) -> &mut F,
+    ) -> Lens<P, F> {
+        Lens {
+            access,
+            getter,
+            getter_mut,
+        }
+    }
+
+    /// returns a clone of the field
+    pub fn get(&self) -> F
+    where
+        F: Clone,
+    {
+        self.get_with(|field| field.clone())
+    }
+
+    pub fn get_with<R, G: FnOnce(&F) -> R>(&self, func: G) -> R {
+        let getter = self.getter;
+        self.access.get_with(|parent| func(getter(parent)))
+    }
+
+    /// sets the field, leaving the rest of the parent state untouched
+    #[track_caller]
+    pub fn set(&self, value: F) {
+        self.update(|field| *field = value);
+    }
+
+    /// updates the field in place using the provided function
+    #[track_caller]
+    pub fn update<G: FnOnce(&mut F)>(&self, func: G) {
+        let getter_mut = self.getter_mut;
+        self.access.update(|parent| func(getter_mut(parent)));
+    }
+}
@@ -1,9 +1,16 @@
 #![feature(track_caller)]
+pub mod form;
 pub mod list;
+pub mod map;
 pub mod prelude;
+pub mod tree;
 pub mod unmount;
 
+mod atom;
 mod helpers;
+mod lens;
+mod runtime;
+mod selector;
 mod state_access;
 mod state_functions;
 mod store;
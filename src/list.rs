@@ -1,11 +1,76 @@
 use crate::state_access::{CloneState, StateAccess};
-use crate::state_functions::use_state;
+use crate::state_functions::{
+    enter_component, purge_subtree, remove_state_with_topo_id, set_state_with_topo_id,
+    state_exists_for_topo_id, update_state_with_topo_id, use_state,
+};
 use slotmap::{new_key_type, DenseSlotMap, Key};
+use std::collections::HashMap;
 
 new_key_type! {
     pub struct ListKey;
 }
 
+/// A structural edit made through a ListControl.
+///
+/// Once `ListControl::enable_change_log` has been called, changes are recorded in
+/// the order they are made and can be drained with `ListControl::drain_changes`,
+/// for instance by a renderer that wants to patch keyed children rather than diff
+/// the whole list. SelectionChanged is only recorded when the selection changes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListChange {
+    Inserted {
+        key: ListKey,
+        idx: usize,
+    },
+    Removed {
+        key: ListKey,
+    },
+    Moved {
+        key: ListKey,
+        from: usize,
+        to: usize,
+    },
+    Replaced {
+        key: ListKey,
+    },
+    SelectionChanged,
+}
+
+// The change log is stored alongside the list in the list's own context.
+struct ListChanges(Vec<ListChange>);
+
+// The contexts each item has been rendered in by for_each_keyed, also stored
+// in the list's own context.
+#[derive(Default)]
+struct ListItemContexts(HashMap<ListKey, Vec<topo::Id>>);
+
+/// Renders every item of the list in its own topological context derived from
+/// the item's ListKey, collecting the results in list order.
+///
+/// State used while rendering an item therefore follows the item when it is
+/// moved, rather than staying at the item's old position.
+#[track_caller]
+pub fn for_each_keyed<T, R, F>(list_control: &ListControl<T>, mut func: F) -> Vec<R>
+where
+    T: Clone + 'static,
+    F: FnMut(ListKey, &T) -> R,
+{
+    let list = list_control.get_list();
+    let list_id = list_control.list_access.id;
+    let callsite = topo::Callsite::here();
+    list.items_order
+        .iter()
+        .filter_map(|key| list.items_map.0.get(*key).map(|item| (*key, item)))
+        .map(|(key, item)| {
+            topo::call_in_slot((callsite, list_id, key), || {
+                list_control.record_item_context(key, topo::Id::current());
+                enter_component("for_each_keyed", || func(key, item))
+            })
+        })
+        .collect()
+}
+
+#[track_caller]
 pub fn use_list<T, F>(initial_list_fn: F) -> ListControl<T>
 where
     F: FnOnce() -> Vec<T>,
@@ -36,13 +101,82 @@ where
         self.list_access.get()
     }
 
-    pub fn clear(&self) {
-        self.list_access.update(|list| {
-            list.items_map = ListKeyDenseSlotMap::new();
-            list.items_order = vec![];
+    /// Starts recording structural edits, read them with drain_changes.
+    /// Nothing is recorded until this is called, so lists that nobody drains
+    /// do not accumulate a log.
+    pub fn enable_change_log(&self) {
+        let id = self.list_access.id;
+        if !state_exists_for_topo_id::<ListChanges>(id) {
+            set_state_with_topo_id(ListChanges(vec![]), id);
+        }
+    }
+
+    /// Stops recording structural edits and discards those not yet drained.
+    pub fn disable_change_log(&self) {
+        remove_state_with_topo_id::<ListChanges>(self.list_access.id);
+    }
+
+    /// Drains the structural edits recorded since the last drain.
+    /// The log keeps growing until it is drained, and is empty unless
+    /// enable_change_log has been called.
+    pub fn drain_changes(&self) -> Vec<ListChange> {
+        let id = self.list_access.id;
+        let mut drained = vec![];
+        if state_exists_for_topo_id::<ListChanges>(id) {
+            update_state_with_topo_id::<ListChanges, _>(id, |changes| {
+                drained = std::mem::take(&mut changes.0)
+            });
+        }
+        drained
+    }
+
+    // Removes the state of every context the item was rendered in by for_each_keyed,
+    // running their unmount handlers, so a reused slot cannot inherit it.
+    fn purge_item_contexts(&self, key: ListKey) {
+        let list_id = self.list_access.id;
+        let mut ids = vec![];
+        if state_exists_for_topo_id::<ListItemContexts>(list_id) {
+            update_state_with_topo_id::<ListItemContexts, _>(list_id, |contexts| {
+                ids = contexts.0.remove(&key).unwrap_or_default();
+            });
+        }
+        for id in ids {
+            purge_subtree(id);
+        }
+    }
+
+    fn record_item_context(&self, key: ListKey, id: topo::Id) {
+        let list_id = self.list_access.id;
+        if !state_exists_for_topo_id::<ListItemContexts>(list_id) {
+            set_state_with_topo_id(ListItemContexts::default(), list_id);
+        }
+        update_state_with_topo_id::<ListItemContexts, _>(list_id, |contexts| {
+            let ids = contexts.0.entry(key).or_default();
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
         });
     }
 
+    fn record_change(&self, change: ListChange) {
+        let id = self.list_access.id;
+        if state_exists_for_topo_id::<ListChanges>(id) {
+            update_state_with_topo_id::<ListChanges, _>(id, |changes| changes.0.push(change));
+        }
+    }
+
+    #[track_caller]
+    pub fn clear(&self) {
+        let mut list = self.list_access.get();
+        let removed_keys = std::mem::take(&mut list.items_order);
+        list.items_map = ListKeyDenseSlotMap::new();
+        self.list_access.set(list);
+        for key in removed_keys {
+            self.purge_item_contexts(key);
+            self.record_change(ListChange::Removed { key });
+        }
+    }
+
     // brain always gets this messed up so I have to write it down!
     // 0 1 2 3 4 5 6
     // a b c d e f g
@@ -72,6 +206,7 @@ where
 
     // 0 1 2 3 4 5 6
     // a b d e f g
+    #[track_caller]
     pub fn move_item_to_position(&self, old_idx: usize, new_idx: usize) {
         let mut list = self.list_access.get();
         if new_idx > list.items_order.len() || old_idx > list.items_order.len() - 1 {
@@ -80,14 +215,22 @@ where
 
         let old_item = list.items_order.remove(old_idx);
         use std::cmp::Ordering;
-        match old_idx.cmp(&new_idx) {
-            Ordering::Less => list.items_order.insert(new_idx - 1, old_item),
-            Ordering::Greater => list.items_order.insert(new_idx, old_item),
-            Ordering::Equal => {}
-        }
+        let to = match old_idx.cmp(&new_idx) {
+            Ordering::Less => new_idx - 1,
+            Ordering::Greater | Ordering::Equal => new_idx,
+        };
+        list.items_order.insert(to, old_item);
         self.list_access.set(list);
+        if to != old_idx {
+            self.record_change(ListChange::Moved {
+                key: old_item,
+                from: old_idx,
+                to,
+            });
+        }
     }
 
+    #[track_caller]
     pub fn move_item_up(&self, old_idx: usize) {
         if old_idx == 0 {
             return;
@@ -95,83 +238,131 @@ where
         self.move_item_to_position(old_idx, old_idx - 1);
     }
 
+    #[track_caller]
     pub fn move_item_down(&self, old_idx: usize) {
         self.move_item_to_position(old_idx, old_idx + 2);
     }
 
+    #[track_caller]
     pub fn insert(&self, idx: usize, item: T) {
         let mut list = self.list_access.get();
         let inserted_key = list.items_map.0.insert(item);
         list.items_order.insert(idx, inserted_key);
         self.list_access.set(list);
+        self.record_change(ListChange::Inserted {
+            key: inserted_key,
+            idx,
+        });
     }
 
+    #[track_caller]
     pub fn remove(&self, idx: usize) -> T {
         let mut list = self.list_access.get();
         let removed_key = list.items_order.remove(idx);
         let obj = list.items_map.0.remove(removed_key).unwrap();
         self.list_access.set(list);
+        self.purge_item_contexts(removed_key);
+        self.record_change(ListChange::Removed { key: removed_key });
         obj
     }
 
+    /// Replaces the item at idx in place, the item keeps its key.
+    #[track_caller]
     pub fn replace(&self, idx: usize, item: T) -> T {
         let mut list = self.list_access.get();
-        let inserted_key = list.items_map.0.insert(item);
-        list.items_order.insert(idx, inserted_key);
-        let replaced_key = list.items_order.remove(idx + 1);
-        let obj = list.items_map.0.remove(replaced_key).unwrap();
+        let replaced_key = list.items_order[idx];
+        let obj = std::mem::replace(&mut list.items_map.0[replaced_key], item);
         self.list_access.set(list);
+        self.record_change(ListChange::Replaced { key: replaced_key });
         obj
     }
 
+    #[track_caller]
     pub fn push(&self, item: T) {
         let mut list = self.list_access.get();
         let pushed_key = list.items_map.0.insert(item);
         list.items_order.push(pushed_key);
+        let idx = list.items_order.len() - 1;
         self.list_access.set(list);
+        self.record_change(ListChange::Inserted {
+            key: pushed_key,
+            idx,
+        });
     }
 
+    #[track_caller]
     pub fn unselect_by_key(&self, key: ListKey) {
         let mut list = self.list_access.get();
+        let selected_before = list.selected_keys.clone();
 
         list.selected_keys.retain(|k| *k != key);
 
+        let selection_changed = list.selected_keys != selected_before;
         self.list_access.set(list);
+        if selection_changed {
+            self.record_change(ListChange::SelectionChanged);
+        }
     }
 
+    #[track_caller]
     pub fn unselect_all(&self) {
         let mut list = self.list_access.get();
+        let selected_before = list.selected_keys.clone();
         list.selected_keys = vec![];
+        let selection_changed = list.selected_keys != selected_before;
         self.list_access.set(list);
+        if selection_changed {
+            self.record_change(ListChange::SelectionChanged);
+        }
     }
 
+    #[track_caller]
     pub fn select_all(&self) {
         let mut list = self.list_access.get();
+        let selected_before = list.selected_keys.clone();
         for key in &list.items_order {
             list.selected_keys.push(*key)
         }
 
+        let selection_changed = list.selected_keys != selected_before;
         self.list_access.set(list);
+        if selection_changed {
+            self.record_change(ListChange::SelectionChanged);
+        }
     }
 
+    #[track_caller]
     pub fn unselect(&self, idx: usize) {
         let mut list = self.list_access.get();
+        let selected_before = list.selected_keys.clone();
 
         list.selected_keys.remove(idx);
 
+        let selection_changed = list.selected_keys != selected_before;
         self.list_access.set(list);
+        if selection_changed {
+            self.record_change(ListChange::SelectionChanged);
+        }
     }
+    #[track_caller]
     pub fn select(&self, idx: usize) {
         let mut list = self.list_access.get();
+        let selected_before = list.selected_keys.clone();
 
         let key = list.items_order[idx];
         list.selected_keys.push(key);
 
+        let selection_changed = list.selected_keys != selected_before;
         self.list_access.set(list);
+        if selection_changed {
+            self.record_change(ListChange::SelectionChanged);
+        }
     }
 
+    #[track_caller]
     pub fn toggle_select(&self, idx: usize) {
         let mut list = self.list_access.get();
+        let selected_before = list.selected_keys.clone();
 
         let key = list.items_order[idx];
         if list.selected_keys.contains(&key) {
@@ -180,36 +371,58 @@ where
             list.selected_keys.push(key);
         }
 
+        let selection_changed = list.selected_keys != selected_before;
         self.list_access.set(list);
+        if selection_changed {
+            self.record_change(ListChange::SelectionChanged);
+        }
     }
 
+    #[track_caller]
     pub fn select_only(&self, idx: usize) {
         let mut list = self.list_access.get();
+        let selected_before = list.selected_keys.clone();
 
         let key = list.items_order[idx];
         list.selected_keys = vec![];
         list.selected_keys.push(key);
 
+        let selection_changed = list.selected_keys != selected_before;
         self.list_access.set(list);
+        if selection_changed {
+            self.record_change(ListChange::SelectionChanged);
+        }
     }
 
+    #[track_caller]
     pub fn select_only_by_key(&self, key: ListKey) {
         let mut list = self.list_access.get();
+        let selected_before = list.selected_keys.clone();
         if !key.is_null() {
             list.selected_keys = vec![];
             list.selected_keys.push(key);
         }
+        let selection_changed = list.selected_keys != selected_before;
         self.list_access.set(list);
+        if selection_changed {
+            self.record_change(ListChange::SelectionChanged);
+        }
     }
 
+    #[track_caller]
     pub fn select_by_key(&self, key: ListKey) {
         let mut list = self.list_access.get();
+        let selected_before = list.selected_keys.clone();
 
         if !key.is_null() {
             list.selected_keys.push(key);
         }
 
+        let selection_changed = list.selected_keys != selected_before;
         self.list_access.set(list);
+        if selection_changed {
+            self.record_change(ListChange::SelectionChanged);
+        }
     }
 }
 
@@ -232,17 +445,18 @@ where
     pub selected_keys: Vec<ListKey>,
 }
 
+// Two maps are equal if they hold the same keys and every key maps to an
+// equal value, so in-place edits are picked up by change detection.
 impl<T> PartialEq for ListKeyDenseSlotMap<T>
 where
-    T: Clone,
+    T: Clone + PartialEq,
 {
     fn eq(&self, other: &Self) -> bool {
-        let mut self_keys = self.0.keys().collect::<Vec<ListKey>>();
-        let mut other_keys = other.0.keys().collect::<Vec<ListKey>>();
-        self_keys.sort();
-        other_keys.sort();
-        self_keys == other_keys
-        // self.isbn == other.isbn
+        self.0.len() == other.0.len()
+            && self
+                .0
+                .iter()
+                .all(|(key, item)| other.0.get(key) == Some(item))
     }
 }
 
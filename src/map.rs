@@ -0,0 +1,219 @@
+use crate::state_access::{CloneState, StateAccess};
+use crate::state_functions::use_state;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+#[track_caller]
+pub fn use_map<K, V, I, F>(initial_map_fn: F) -> MapControl<K, V>
+where
+    F: FnOnce() -> I,
+    I: IntoIterator<Item = (K, V)>,
+    K: Clone + Eq + Hash + 'static,
+    V: Clone + 'static,
+{
+    let map_access = use_state(|| Map::new(initial_map_fn()));
+
+    MapControl::new(map_access)
+}
+
+#[derive(Clone)]
+pub struct MapControl<K, V>
+where
+    K: Clone + Eq + Hash + 'static,
+    V: Clone + 'static,
+{
+    map_access: StateAccess<Map<K, V>>,
+}
+
+impl<K, V> MapControl<K, V>
+where
+    K: Clone + Eq + Hash + 'static,
+    V: Clone + 'static,
+{
+    fn new(map_access: StateAccess<Map<K, V>>) -> MapControl<K, V> {
+        MapControl { map_access }
+    }
+
+    pub fn get_map(&self) -> Map<K, V> {
+        self.map_access.get()
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        self.map_access.get_with(|map| map.get(key).cloned())
+    }
+
+    #[track_caller]
+    pub fn clear(&self) {
+        self.map_access.update(|map| {
+            map.items.clear();
+            map.keys_order = vec![];
+            map.selected_keys = vec![];
+        });
+    }
+
+    /// inserts an item for the key, returning the previous item if there was one.
+    /// A new key is appended to the end of the iteration order, an existing key keeps
+    /// its position.
+    #[track_caller]
+    pub fn insert(&self, key: K, item: V) -> Option<V> {
+        let mut map = self.map_access.get();
+        let old_item = map.items.insert(key.clone(), item);
+        if old_item.is_none() {
+            map.keys_order.push(key);
+        }
+        self.map_access.set(map);
+        old_item
+    }
+
+    #[track_caller]
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let mut map = self.map_access.get();
+        let obj = map.items.remove(key);
+        if obj.is_some() {
+            map.keys_order.retain(|k| k != key);
+            map.selected_keys.retain(|k| k != key);
+        }
+        self.map_access.set(map);
+        obj
+    }
+
+    /// updates the item for the key in place using the provided function.
+    /// Does nothing if the key is not present.
+    #[track_caller]
+    pub fn update_entry<F: FnOnce(&mut V)>(&self, key: &K, func: F) {
+        self.map_access.update(|map| {
+            if let Some(item) = map.items.get_mut(key) {
+                func(item);
+            }
+        });
+    }
+
+    /// keeps only the entries for which the provided function returns true.
+    #[track_caller]
+    pub fn retain<F: FnMut(&K, &V) -> bool>(&self, mut func: F) {
+        self.map_access.update(|map| {
+            map.items.retain(|k, v| func(k, v));
+            let items = &map.items;
+            map.keys_order.retain(|k| items.contains_key(k));
+            map.selected_keys.retain(|k| items.contains_key(k));
+        });
+    }
+
+    #[track_caller]
+    pub fn unselect(&self, key: &K) {
+        self.map_access
+            .update(|map| map.selected_keys.retain(|k| k != key));
+    }
+
+    #[track_caller]
+    pub fn unselect_all(&self) {
+        self.map_access.update(|map| map.selected_keys = vec![]);
+    }
+
+    #[track_caller]
+    pub fn select_all(&self) {
+        self.map_access
+            .update(|map| map.selected_keys = map.keys_order.clone());
+    }
+
+    #[track_caller]
+    pub fn select(&self, key: K) {
+        self.map_access.update(|map| {
+            if map.items.contains_key(&key) && !map.selected_keys.contains(&key) {
+                map.selected_keys.push(key);
+            }
+        });
+    }
+
+    #[track_caller]
+    pub fn toggle_select(&self, key: K) {
+        self.map_access.update(|map| {
+            if map.selected_keys.contains(&key) {
+                map.selected_keys.retain(|k| *k != key);
+            } else if map.items.contains_key(&key) {
+                map.selected_keys.push(key);
+            }
+        });
+    }
+
+    #[track_caller]
+    pub fn select_only(&self, key: K) {
+        self.map_access.update(|map| {
+            if map.items.contains_key(&key) {
+                map.selected_keys = vec![key];
+            }
+        });
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Map<K, V>
+where
+    K: Clone + Eq + Hash + 'static,
+    V: Clone + 'static,
+{
+    pub items: HashMap<K, V>,
+    pub keys_order: Vec<K>,
+    pub selected_keys: Vec<K>,
+}
+
+impl<K, V> Map<K, V>
+where
+    K: Clone + Eq + Hash + 'static,
+    V: Clone + 'static,
+{
+    fn new<I: IntoIterator<Item = (K, V)>>(entries: I) -> Map<K, V> {
+        let mut items = HashMap::new();
+        let mut keys_order = vec![];
+        for (key, item) in entries {
+            if items.insert(key.clone(), item).is_none() {
+                keys_order.push(key);
+            }
+        }
+        Map {
+            items,
+            keys_order,
+            selected_keys: vec![],
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.items.get(key)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.items.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys_order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys_order.is_empty()
+    }
+
+    // an iterator over all entries in insertion order
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.keys_order
+            .iter()
+            .filter_map(move |key| self.items.get(key).map(|item| (key, item)))
+    }
+
+    // an iterator over all keys in insertion order
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.keys_order.iter()
+    }
+
+    // an iterator over all items in insertion order
+    pub fn items(&self) -> impl Iterator<Item = &V> {
+        self.iter().map(|(_, item)| item)
+    }
+
+    // an iterator over all selected entries
+    pub fn selected(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.selected_keys
+            .iter()
+            .filter_map(move |key| self.items.get(key).map(|item| (key, item)))
+    }
+}
@@ -28,11 +28,23 @@
 pub use topo;
 
 // Re exports
+pub use crate::atom::{use_atom, Atom, AtomAccess};
 pub use crate::helpers::do_once;
-pub use crate::state_access::{ChangedState, CloneState, StateAccess};
+pub use crate::lens::Lens;
+pub use crate::runtime::Runtime;
+pub use crate::selector::{selector, use_selector, Selector, SelectorGet};
+pub use crate::state_access::{
+    use_previous, ChangedState, CloneState, StaleStateAccess, StateAccess,
+};
 pub use crate::state_functions::{
-    clone_state_with_topo_id, execute_and_remove_unmounts, new_state, on_unmount,
-    purge_and_reset_unseen_ids, reset_unseen_id_list, set_state_with_topo_id,
-    state_exists_for_topo_id, unseen_ids, update_state_with_topo_id, use_state, use_state_current,
+    clone_state_with_topo_id, component_name, component_parent, current_frame, enter_component,
+    execute_and_remove_unmounts, for_each_state, for_each_state_mut, gc_policy, ids_to_purge,
+    iter_states, last_write_location, new_state, on_mount, on_unmount, on_update,
+    purge_and_reset_unseen_ids, remove_all_state_for_id, reset_subtree, reset_unseen_id_list,
+    set_gc_policy, set_keep_alive, set_state_with_topo_id, set_write_log_enabled,
+    state_exists_for_topo_id, subtree_ids, take_dirty_ids, take_write_log, unseen_ids,
+    update_state_with_topo_id, use_state, use_state_current,
 };
+pub use crate::store::{GcPolicy, StateWrite, WriteKind};
 pub use crate::unmount::{StateAccessUnmount, Unmount};
+pub use comp_state_macros::{component, CompState};
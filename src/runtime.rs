@@ -0,0 +1,53 @@
+use crate::state_functions::{
+    execute_and_remove_unmounts, purge_and_reset_unseen_ids, reset_unseen_id_list,
+};
+
+/// Drives frames, so that the unseen id bookkeeping, unmount handlers and
+/// garbage collection always happen in the right order.
+///
+/// Each call of run_frame
+///
+/// 1. runs the pre-frame hooks
+/// 2. resets the unseen id list
+/// 3. renders the root under a stable topological root
+/// 4. executes the unmount handlers of ids that were not seen, children first
+/// 5. purges the state of ids that were not seen and advances the frame
+/// 6. runs the post-frame hooks
+#[derive(Default)]
+pub struct Runtime {
+    pre_frame_hooks: Vec<Box<dyn FnMut()>>,
+    post_frame_hooks: Vec<Box<dyn FnMut()>>,
+}
+
+impl Runtime {
+    pub fn new() -> Runtime {
+        Runtime::default()
+    }
+
+    /// adds a hook that runs at the start of every frame, before rendering
+    pub fn on_pre_frame<F: FnMut() + 'static>(&mut self, hook: F) {
+        self.pre_frame_hooks.push(Box::new(hook));
+    }
+
+    /// adds a hook that runs at the end of every frame, after garbage collection
+    pub fn on_post_frame<F: FnMut() + 'static>(&mut self, hook: F) {
+        self.post_frame_hooks.push(Box::new(hook));
+    }
+
+    /// Runs a single frame rendered by root and returns what root returns.
+    pub fn run_frame<R, F: FnOnce() -> R>(&mut self, root: F) -> R {
+        for hook in &mut self.pre_frame_hooks {
+            hook();
+        }
+
+        reset_unseen_id_list();
+        let rendered = topo::call_in_slot("comp_state_root", root);
+        execute_and_remove_unmounts();
+        purge_and_reset_unseen_ids();
+
+        for hook in &mut self.post_frame_hooks {
+            hook();
+        }
+        rendered
+    }
+}
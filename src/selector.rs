@@ -0,0 +1,101 @@
+use crate::atom::Atom;
+use crate::state_functions::{cache_selector, cached_selector, read_atom, subscribe_to_atoms};
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+/// State derived from one or more atoms.
+///
+/// The value is computed on first use and cached until one of the atoms it
+/// read changes. Selectors are identified by their address, so they have to be
+/// declared as a `static`, which the `&'static` receivers enforce.
+///
+/// # Examples
+///
+/// ```
+/// # use comp_state::{atom, selector, use_selector, Selector};
+/// atom! {
+///     Messages: Vec<(String, bool)> = vec![];
+/// }
+///
+/// static UNREAD: Selector<usize> =
+///     selector(|get| get.atom_with::<Messages, _, _>(|m| m.iter().filter(|(_, read)| !read).count()));
+///
+/// let unread = use_selector(&UNREAD);
+/// ```
+pub struct Selector<T> {
+    compute: fn(&SelectorGet) -> T,
+}
+
+pub const fn selector<T>(compute: fn(&SelectorGet) -> T) -> Selector<T> {
+    Selector { compute }
+}
+
+impl<T> std::fmt::Debug for Selector<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(Selector {:#x})", self.key())
+    }
+}
+
+impl<T> Selector<T> {
+    // function addresses are not guaranteed to be unique, identical functions can
+    // be merged, but every static has its own address. Only called on &'static
+    // selectors, a temporary one could reuse the address of another.
+    fn key(&self) -> usize {
+        self as *const Selector<T> as usize
+    }
+}
+
+impl<T: 'static + Clone> Selector<T> {
+    /// returns the cached value, computing it first if an input atom has changed
+    pub fn get(&'static self) -> T {
+        self.get_with_dependencies().0
+    }
+
+    fn get_with_dependencies(&'static self) -> (T, HashSet<TypeId>) {
+        if let Some(cached) = cached_selector::<T>(self.key()) {
+            return cached;
+        }
+        let get = SelectorGet {
+            dependencies: RefCell::new(HashSet::new()),
+        };
+        let value = (self.compute)(&get);
+        let dependencies = get.dependencies.into_inner();
+        cache_selector(self.key(), value.clone(), dependencies.clone());
+        (value, dependencies)
+    }
+}
+
+/// Passed to a selector's function to read atoms, recording them as inputs.
+pub struct SelectorGet {
+    dependencies: RefCell<HashSet<TypeId>>,
+}
+
+impl SelectorGet {
+    pub fn atom<A: Atom>(&self) -> A::Value
+    where
+        A::Value: Clone,
+    {
+        self.atom_with::<A, _, _>(|value| value.clone())
+    }
+
+    pub fn atom_with<A: Atom, R, F: FnOnce(&A::Value) -> R>(&self, func: F) -> R {
+        self.dependencies.borrow_mut().insert(TypeId::of::<A>());
+        read_atom::<A, _, _>(func)
+    }
+
+    /// reads another selector, its atoms become inputs of this selector too
+    pub fn selector<T: 'static + Clone>(&self, selector: &'static Selector<T>) -> T {
+        let (value, dependencies) = selector.get_with_dependencies();
+        self.dependencies.borrow_mut().extend(dependencies);
+        value
+    }
+}
+
+/// Returns the value of the selector and records the calling context as
+/// depending on its atoms, so it shows up in `take_dirty_ids` once they change.
+pub fn use_selector<T: 'static + Clone>(selector: &'static Selector<T>) -> T {
+    let (value, dependencies) = selector.get_with_dependencies();
+    subscribe_to_atoms(&dependencies.into_iter().collect::<Vec<_>>());
+    value
+}
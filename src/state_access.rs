@@ -1,5 +1,10 @@
+use crate::lens::Lens;
 use crate::state_functions::*;
+use crate::store::WriteKind;
+use slotmap::{DefaultKey, Key};
 use std::marker::PhantomData;
+use std::panic::Location;
+use std::rc::Rc;
 
 ///  Accessor struct that provides access to getting and setting the
 ///  state of the stored type
@@ -7,9 +12,31 @@ use std::marker::PhantomData;
 // #[derive(Debug)]
 pub struct StateAccess<T> {
     pub id: topo::Id,
+    // the slotmap key the id had when this accessor was made, a later context
+    // re-created at the same id gets a different key. Null if there was no state yet.
+    key: DefaultKey,
     _phantom_data: PhantomData<T>,
 }
 
+/// Returned when writing through a StateAccess whose context has been purged and
+/// re-created since the accessor was made.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StaleStateAccess {
+    pub id: topo::Id,
+}
+
+impl std::fmt::Display for StaleStateAccess {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Stale StateAccess: the state for {:?} was purged and its context re-created",
+            self.id
+        )
+    }
+}
+
+impl std::error::Error for StaleStateAccess {}
+
 impl<T> std::fmt::Debug for StateAccess<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "({:#?})", self.id)
@@ -21,6 +48,7 @@ impl<T> Clone for StateAccess<T> {
     fn clone(&self) -> StateAccess<T> {
         StateAccess::<T> {
             id: self.id,
+            key: self.key,
             _phantom_data: PhantomData::<T>,
         }
     }
@@ -30,44 +58,190 @@ impl<T> StateAccess<T>
 where
     T: 'static,
 {
+    /// Makes an accessor for the state of the id.
+    ///
+    /// Staleness is detected against the state the id holds at this point. An
+    /// accessor made before the id holds any state is never checked for
+    /// staleness, it keeps writing to the id even after the context has been
+    /// purged and re-created. Make accessors after the state is set, as use_state
+    /// does, to have them checked.
     pub fn new(id: topo::Id) -> StateAccess<T> {
         StateAccess {
             id,
+            key: key_for_topo_id(id),
             _phantom_data: PhantomData,
         }
     }
 
+    /// Whether the context this accessor was made for still holds the state.
+    ///
+    /// False once the context has been purged, e.g. by garbage collection or
+    /// reset_subtree, even if it has since been re-created with new state. The
+    /// accessor is then stale. State removed with `remove` and later set again
+    /// through the same context is the same context, so it counts as alive again.
+    pub fn is_alive(self) -> bool {
+        self.state_exists()
+    }
+
+    // the id has not been purged and re-created since this accessor was made,
+    // always true for accessors made before the id held state
+    fn is_current(self) -> bool {
+        self.key.is_null() || key_for_topo_id(self.id) == self.key
+    }
+
+    fn check_current(self) -> Result<(), StaleStateAccess> {
+        if self.is_current() {
+            Ok(())
+        } else {
+            Err(StaleStateAccess { id: self.id })
+        }
+    }
+
     // stores a value of type T in a backing Store
+    // a stale accessor acts as if its state is gone, so the write is ignored.
+    // Debug builds panic instead, use try_set to handle it.
+    #[track_caller]
     pub fn set(self, value: T) {
+        let result = self.set_at(value, Location::caller());
+        debug_assert!(result.is_ok(), "{}", result.unwrap_err());
+    }
+
+    /// sets the state, or returns an error if the accessor is stale
+    #[track_caller]
+    pub fn try_set(self, value: T) -> Result<(), StaleStateAccess> {
+        self.set_at(value, Location::caller())
+    }
+
+    fn set_at(
+        self,
+        value: T,
+        location: &'static Location<'static>,
+    ) -> Result<(), StaleStateAccess> {
+        self.check_current()?;
+        record_write::<T>(self.id, WriteKind::Set, location);
         set_state_with_topo_id(value, self.id);
+        Ok(())
     }
 
+    /// removes the stored state, a stale accessor removes nothing
+    #[track_caller]
     pub fn remove(self) -> Option<T> {
-        remove_state_with_topo_id(self.id)
+        if self.is_current() {
+            record_write::<T>(self.id, WriteKind::Remove, Location::caller());
+            remove_state_with_topo_id(self.id)
+        } else {
+            None
+        }
     }
 
+    #[track_caller]
     pub fn delete(self) {
         self.remove();
     }
 
+    /// exempts this state from garbage collection, it stays until removed
+    pub fn keep_alive(self) -> Self {
+        set_keep_alive(self.id, true);
+        self
+    }
+
+    #[track_caller]
     pub fn reset_on_unmount(self) -> Self {
         on_unmount(move || self.delete());
         self
     }
 
     /// updates the stored state in place
-    /// using the provided function
+    /// using the provided function, a stale accessor ignores the update like set
+    #[track_caller]
     pub fn update<F: FnOnce(&mut T) -> ()>(self, func: F) {
+        let result = self.update_at(func, Location::caller());
+        debug_assert!(result.is_ok(), "{}", result.unwrap_err());
+    }
+
+    /// updates the state, or returns an error if the accessor is stale
+    #[track_caller]
+    pub fn try_update<F: FnOnce(&mut T)>(self, func: F) -> Result<(), StaleStateAccess> {
+        self.update_at(func, Location::caller())
+    }
+
+    fn update_at<F: FnOnce(&mut T)>(
+        self,
+        func: F,
+        location: &'static Location<'static>,
+    ) -> Result<(), StaleStateAccess> {
+        self.check_current()?;
+        record_write::<T>(self.id, WriteKind::Update, location);
         update_state_with_topo_id(self.id, func);
+        Ok(())
     }
 
+    /// false for a stale accessor
     pub fn state_exists(self) -> bool {
-        state_exists_for_topo_id::<T>(self.id)
+        self.is_current() && state_exists_for_topo_id::<T>(self.id)
     }
 
+    /// panics if the state is not stored, or the accessor is stale
     pub fn get_with<F: FnOnce(&T) -> R, R>(self, func: F) -> R {
+        if let Err(stale) = self.check_current() {
+            panic!("{}", stale);
+        }
         read_state_with_topo_id(self.id, func)
     }
+
+    /// Builds a callback that updates the stored state with each event it is called
+    /// with, for framework adapters to wrap as event handlers.
+    ///
+    /// Writes made by the callback are recorded as made where it was built. Once
+    /// the accessor is stale the callback does nothing, as event handlers often
+    /// outlive the component they were built in.
+    #[track_caller]
+    pub fn callback<E, F>(self, func: F) -> Rc<dyn Fn(E)>
+    where
+        E: 'static,
+        F: Fn(&mut T, E) + 'static,
+    {
+        let location = Location::caller();
+        Rc::new(move |event| {
+            let _ = self.update_at(|state| func(state, event), location);
+        })
+    }
+
+    /// Builds a callback that sets the stored state to the value it is called with.
+    /// Like callback it does nothing once the accessor is stale.
+    #[track_caller]
+    pub fn setter(self) -> Rc<dyn Fn(T)> {
+        let location = Location::caller();
+        Rc::new(move |value| {
+            let _ = self.set_at(value, location);
+        })
+    }
+
+    /// Derives an accessor for a single field of the stored state.
+    ///
+    /// ```ignore
+    /// let title = model.lens(|m| &m.title, |m| &mut m.title);
+    /// title.set("foo".to_string());
+    /// ```
+    pub fn lens<F: 'static>(
+        self,
+        getter: fn(&T) -> &F,
+        getter_mut: fn(&mut T) -> &mut F,
+    ) -> Lens<T, F> {
+        Lens::new(self, getter, getter_mut)
+    }
+}
+
+impl StateAccess<bool> {
+    /// Builds a callback that flips the stored bool whatever event it is called with.
+    /// Like callback it does nothing once the accessor is stale.
+    #[track_caller]
+    pub fn toggler<E: 'static>(self) -> Rc<dyn Fn(E)> {
+        let location = Location::caller();
+        Rc::new(move |_| {
+            let _ = self.update_at(|state| *state = !*state, location);
+        })
+    }
 }
 
 pub trait CloneState<T>
@@ -83,18 +257,60 @@ impl<T> CloneState<T> for StateAccess<T>
 where
     T: Clone + 'static,
 {
-    /// returns a clone of the stored state panics if not stored.
+    /// returns a clone of the stored state panics if not stored, or if the
+    /// accessor is stale.
     fn get(&self) -> T {
+        if let Err(stale) = self.check_current() {
+            panic!("{}", stale);
+        }
         clone_state_with_topo_id::<T>(self.id).expect("state should be present")
     }
 
+    /// None if the state is not stored or the accessor is stale
     fn soft_get(&self) -> Option<T> {
-        clone_state_with_topo_id::<T>(self.id)
+        if self.is_current() {
+            clone_state_with_topo_id::<T>(self.id)
+        } else {
+            None
+        }
     }
 }
 
+// Snapshots of a state taken by changed and use_previous, the latest one from
+// the current frame and the last one from the frame before it was observed.
 #[derive(Clone)]
-struct ChangedWrapper<T>(T);
+struct ChangedWrapper<T> {
+    frame: u64,
+    previous: Option<T>,
+    current: T,
+}
+
+impl<T> StateAccess<T>
+where
+    T: Clone + 'static,
+{
+    // records the current value and returns the value observed in the previous frame
+    fn observe(self) -> Option<T> {
+        let frame = current_frame();
+        let current = self.get();
+        let wrapper = match remove_state_with_topo_id::<ChangedWrapper<T>>(self.id) {
+            Some(wrapper) if wrapper.frame == frame => ChangedWrapper { current, ..wrapper },
+            Some(wrapper) => ChangedWrapper {
+                frame,
+                previous: Some(wrapper.current),
+                current,
+            },
+            None => ChangedWrapper {
+                frame,
+                previous: None,
+                current,
+            },
+        };
+        let previous = wrapper.previous.clone();
+        set_state_with_topo_id(wrapper, self.id);
+        previous
+    }
+}
 
 pub trait ChangedState {
     fn changed(&self) -> bool;
@@ -104,16 +320,27 @@ impl<T> ChangedState for StateAccess<T>
 where
     T: Clone + 'static + PartialEq,
 {
+    /// whether the state differs from its value in the previous frame,
+    /// true the first time it is checked
     fn changed(&self) -> bool {
-        if let Some(old_state) = clone_state_with_topo_id::<ChangedWrapper<T>>(self.id) {
-            old_state.0 != self.get()
-        } else {
-            set_state_with_topo_id(ChangedWrapper(self.get()), self.id);
-            true
-        }
+        self.observe().is_none_or(|previous| previous != self.get())
     }
 }
 
+/// Returns the value the state had the previous frame it was rendered in,
+/// None on the first frame. For instance to react to something that just became
+/// visible:
+///
+/// ```ignore
+/// let visible = use_state(|| false);
+/// if visible.get() && use_previous(&visible) == Some(false) {
+///     // ...
+/// }
+/// ```
+pub fn use_previous<T: Clone + 'static>(access: &StateAccess<T>) -> Option<T> {
+    access.observe()
+}
+
 impl<T> std::fmt::Display for StateAccess<T>
 where
     T: std::fmt::Display + 'static,
@@ -123,10 +350,18 @@ where
     }
 }
 
+use std::cmp::Ordering;
 use std::ops::Add;
+use std::ops::AddAssign;
 use std::ops::Div;
+use std::ops::DivAssign;
 use std::ops::Mul;
+use std::ops::MulAssign;
+use std::ops::Neg;
+use std::ops::Rem;
+use std::ops::RemAssign;
 use std::ops::Sub;
+use std::ops::SubAssign;
 
 impl<T> Add for StateAccess<T>
 where
@@ -157,7 +392,7 @@ where
     type Output = T;
 
     fn div(self, other: Self) -> Self::Output {
-        self.get_with(|s| other.get_with(|o| *o / *s))
+        self.get_with(|s| other.get_with(|o| *s / *o))
     }
 }
 
@@ -168,6 +403,101 @@ where
     type Output = T;
 
     fn sub(self, other: Self) -> Self::Output {
-        self.get_with(|s| other.get_with(|o| *o - *s))
+        self.get_with(|s| other.get_with(|o| *s - *o))
+    }
+}
+
+impl<T> Rem for StateAccess<T>
+where
+    T: Copy + Rem<Output = T> + 'static,
+{
+    type Output = T;
+
+    fn rem(self, other: Self) -> Self::Output {
+        self.get_with(|s| other.get_with(|o| *s % *o))
+    }
+}
+
+impl<T> Neg for StateAccess<T>
+where
+    T: Copy + Neg<Output = T> + 'static,
+{
+    type Output = T;
+
+    fn neg(self) -> Self::Output {
+        self.get_with(|s| -*s)
+    }
+}
+
+// The compound assignment operators write back to the store,
+// i.e. `count += 1` updates the stored count.
+
+impl<T> AddAssign<T> for StateAccess<T>
+where
+    T: AddAssign + 'static,
+{
+    #[track_caller]
+    fn add_assign(&mut self, other: T) {
+        self.update(|s| *s += other);
+    }
+}
+
+impl<T> SubAssign<T> for StateAccess<T>
+where
+    T: SubAssign + 'static,
+{
+    #[track_caller]
+    fn sub_assign(&mut self, other: T) {
+        self.update(|s| *s -= other);
+    }
+}
+
+impl<T> MulAssign<T> for StateAccess<T>
+where
+    T: MulAssign + 'static,
+{
+    #[track_caller]
+    fn mul_assign(&mut self, other: T) {
+        self.update(|s| *s *= other);
+    }
+}
+
+impl<T> DivAssign<T> for StateAccess<T>
+where
+    T: DivAssign + 'static,
+{
+    #[track_caller]
+    fn div_assign(&mut self, other: T) {
+        self.update(|s| *s /= other);
+    }
+}
+
+impl<T> RemAssign<T> for StateAccess<T>
+where
+    T: RemAssign + 'static,
+{
+    #[track_caller]
+    fn rem_assign(&mut self, other: T) {
+        self.update(|s| *s %= other);
+    }
+}
+
+// Comparisons against plain values, i.e. `if count > 3 {..}`
+
+impl<T> PartialEq<T> for StateAccess<T>
+where
+    T: PartialEq + 'static,
+{
+    fn eq(&self, other: &T) -> bool {
+        self.get_with(|s| s == other)
+    }
+}
+
+impl<T> PartialOrd<T> for StateAccess<T>
+where
+    T: PartialOrd + 'static,
+{
+    fn partial_cmp(&self, other: &T) -> Option<Ordering> {
+        self.get_with(|s| s.partial_cmp(other))
     }
 }
@@ -1,9 +1,12 @@
+use crate::atom::Atom;
 use crate::state_access::CloneState;
 use crate::state_access::StateAccess;
-use crate::store::Store;
+use crate::store::{GcPolicy, StateWrite, Store, WriteKind};
 use crate::unmount::Unmount;
+use std::any::TypeId;
 use std::cell::RefCell;
 use std::collections::HashSet;
+use std::rc::Rc;
 
 thread_local! {
     static STORE: RefCell<Store> = RefCell::new(Store::new());
@@ -33,10 +36,22 @@ thread_local! {
 ///
 /// You can store Clone or non-Clone types. Although non-Clone types need
 /// to be read via their accessor in a more restrictive way.
-// in a parent context.
-#[topo::nested]
+///
+/// Like a `#[topo::nested]` function the state lives in its own context nested
+/// in the calling context, the calling context is recorded as its parent.
+///
+/// Custom hooks wrapping use_state should be `#[track_caller]` too, so that
+/// their state is keyed to where they are called from and they pass the hook
+/// rule checks.
+#[track_caller]
 pub fn use_state<T: 'static, F: FnOnce() -> T>(data_fn: F) -> StateAccess<T> {
-    use_state_current(data_fn)
+    let parent_id = topo::Id::current();
+    #[cfg(debug_assertions)]
+    check_hook_rules(parent_id, std::panic::Location::caller());
+    topo::call(|| {
+        register_child(parent_id, topo::Id::current());
+        use_state_current(data_fn)
+    })
 }
 
 ///
@@ -54,11 +69,56 @@ pub fn use_state_current<T: 'static, F: FnOnce() -> T>(data_fn: F) -> StateAcces
     StateAccess::new(current_id)
 }
 
-#[topo::nested]
+// Number of new_state calls made by a context in the given frame and render.
+struct NewStateCount {
+    frame: u64,
+    render: u64,
+    count: usize,
+}
+
+///
+/// Constructs a T accessor in a slot chosen by call order, like React's hooks.
+///
+/// The Nth call of new_state made by a context in a render always maps to the same
+/// slot. The count starts again whenever the component the context belongs to
+/// starts rendering, and at every frame boundary. A context that is not inside a
+/// `#[component]` therefore has to be rendered once per frame, i.e. each render
+/// has to end with purge_and_reset_unseen_ids, as Runtime::run_frame does.
+/// Slots that are not used in a frame are garbage collected like any other
+/// unseen state.
 pub fn new_state<T: 'static, F: FnOnce() -> T>(data_fn: F) -> StateAccess<T> {
-    let count = use_state(|| 0);
-    count.update(|c| *c += 1);
-    topo::call_in_slot(count.get(), || use_state_current(data_fn))
+    let current_id = topo::Id::current();
+    let frame = current_frame();
+    let render = STORE.with(|store_refcell| store_refcell.borrow().render_of(current_id));
+    if !state_exists_for_topo_id::<NewStateCount>(current_id) {
+        set_state_with_topo_id(
+            NewStateCount {
+                frame,
+                render,
+                count: 0,
+            },
+            current_id,
+        );
+    }
+    let mut slot = 0;
+    update_state_with_topo_id::<NewStateCount, _>(current_id, |new_state_count| {
+        if new_state_count.frame != frame || new_state_count.render != render {
+            new_state_count.frame = frame;
+            new_state_count.render = render;
+            new_state_count.count = 0;
+        }
+        new_state_count.count += 1;
+        slot = new_state_count.count;
+    });
+    topo::call_in_slot(slot, || {
+        register_child(current_id, topo::Id::current());
+        use_state_current(data_fn)
+    })
+}
+
+/// The current frame, frames are advanced by purge_and_reset_unseen_ids.
+pub fn current_frame() -> u64 {
+    STORE.with(|store_refcell| store_refcell.borrow().frame)
 }
 
 /// Sets the state of type T keyed to the given TopoId
@@ -70,6 +130,87 @@ pub fn set_state_with_topo_id<T: 'static>(data: T, current_id: topo::Id) {
     })
 }
 
+// In debug builds a component rendered through `enter_component` panics if it calls
+// the same hook more than once, which happens when a hook is used inside a loop
+// without a key.
+//
+// Hooks are told apart by the source location they are called from, so a plain
+// function wrapping a hook looks the same when called twice as a hook in a loop.
+// Custom hooks have to be `#[track_caller]` or `#[topo::nested]` to be checked
+// correctly.
+#[cfg(debug_assertions)]
+fn check_hook_rules(parent_id: topo::Id, location: &'static std::panic::Location<'static>) {
+    let result = STORE.with(|store_refcell| {
+        store_refcell
+            .borrow_mut()
+            .check_hook_callsite(parent_id, location)
+    });
+    if let Err(name) = result {
+        panic!(
+            "hook called more than once from {} while rendering component `{}`. \
+             Hooks inside loops must be keyed, for instance with topo::call_in_slot. \
+             If the hook is called from a custom hook function, mark that function \
+             #[track_caller] or #[topo::nested]",
+            location, name
+        );
+    }
+}
+
+/// Renders a component, registering it in the store's component tree under the
+/// name given, typically the function name.
+///
+/// This is what the `#[component]` attribute expands to and it should be called
+/// from within the component's own topological context.
+pub fn enter_component<R, F: FnOnce() -> R>(name: &'static str, body: F) -> R {
+    struct ExitGuard(topo::Id);
+
+    impl Drop for ExitGuard {
+        fn drop(&mut self) {
+            STORE.with(|store_refcell| {
+                if let Ok(mut store) = store_refcell.try_borrow_mut() {
+                    store.exit_component(self.0);
+                }
+            })
+        }
+    }
+
+    let id = topo::Id::current();
+    STORE.with(|store_refcell| store_refcell.borrow_mut().enter_component(id, name));
+    let _guard = ExitGuard(id);
+    body()
+}
+
+/// The name of the component rendered with the given id, if it was rendered
+/// through `enter_component`.
+pub fn component_name(id: topo::Id) -> Option<&'static str> {
+    STORE.with(|store_refcell| {
+        store_refcell
+            .borrow()
+            .id_nodes
+            .get(&id)
+            .and_then(|node| node.name)
+    })
+}
+
+/// The parent of a context in the store's component tree.
+pub fn component_parent(id: topo::Id) -> Option<topo::Id> {
+    STORE.with(|store_refcell| {
+        store_refcell
+            .borrow()
+            .id_nodes
+            .get(&id)
+            .and_then(|node| node.parent)
+    })
+}
+
+fn register_child(parent_id: topo::Id, child_id: topo::Id) {
+    STORE.with(|store_refcell| {
+        store_refcell
+            .borrow_mut()
+            .register_child(parent_id, child_id)
+    })
+}
+
 pub fn state_exists_for_topo_id<T: 'static>(id: topo::Id) -> bool {
     STORE.with(|store_refcell| store_refcell.borrow().state_exists_with_topo_id::<T>(id))
 }
@@ -88,6 +229,56 @@ pub fn clone_state_with_topo_id<T: 'static + Clone>(id: topo::Id) -> Option<T> {
     })
 }
 
+pub(crate) fn record_write<T: 'static>(
+    id: topo::Id,
+    kind: WriteKind,
+    location: &'static std::panic::Location<'static>,
+) {
+    STORE.with(|store_refcell| {
+        store_refcell
+            .borrow_mut()
+            .record_write::<T>(id, kind, location)
+    });
+}
+
+/// Where the state of type T for the id was last set, updated or removed through
+/// its StateAccess. Useful to find out which callback changed a value.
+pub fn last_write_location<T: 'static>(
+    id: topo::Id,
+) -> Option<&'static std::panic::Location<'static>> {
+    STORE.with(|store_refcell| store_refcell.borrow().last_write_location::<T>(id))
+}
+
+/// Starts or stops logging every write made through a StateAccess.
+/// Stopping discards the writes logged so far.
+pub fn set_write_log_enabled(enabled: bool) {
+    STORE.with(|store_refcell| {
+        let mut store_mut = store_refcell.borrow_mut();
+        if !enabled {
+            store_mut.write_log = None;
+        } else if store_mut.write_log.is_none() {
+            store_mut.write_log = Some(vec![]);
+        }
+    });
+}
+
+/// Returns the writes logged since the last call, oldest first.
+pub fn take_write_log() -> Vec<StateWrite> {
+    STORE.with(|store_refcell| {
+        store_refcell
+            .borrow_mut()
+            .write_log
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    })
+}
+
+// The slotmap key currently assigned to the id, null if it holds no state.
+pub(crate) fn key_for_topo_id(id: topo::Id) -> slotmap::DefaultKey {
+    STORE.with(|store_refcell| store_refcell.borrow().key_for_id(id))
+}
+
 pub fn remove_state_with_topo_id<T: 'static>(id: topo::Id) -> Option<T> {
     STORE.with(|store_refcell| {
         store_refcell
@@ -96,6 +287,43 @@ pub fn remove_state_with_topo_id<T: 'static>(id: topo::Id) -> Option<T> {
     })
 }
 
+/// Clones every stored state of type T, across all components, together with
+/// the id that owns it. For instance to find the focused input or collect all
+/// validation errors.
+///
+/// Querying does not count as the ids being seen for garbage collection.
+pub fn iter_states<T: 'static + Clone>() -> Vec<(topo::Id, T)> {
+    let mut states = vec![];
+    for_each_state::<T, _>(|id, value| states.push((id, value.clone())));
+    states
+}
+
+/// Calls func with every stored state of type T and the id that owns it.
+///
+/// The store is borrowed while func runs, so func must not access state itself.
+pub fn for_each_state<T: 'static, F: FnMut(topo::Id, &T)>(func: F) {
+    STORE.with(|store_refcell| store_refcell.borrow().for_each_state(func))
+}
+
+/// Like for_each_state but allows the states to be modified in place,
+/// e.g. to close all open popovers.
+pub fn for_each_state_mut<T: 'static, F: FnMut(topo::Id, &mut T)>(func: F) {
+    STORE.with(|store_refcell| store_refcell.borrow_mut().for_each_state_mut(func))
+}
+
+/// Removes the state of every type stored for the id, e.g. to forget a component
+/// without knowing all the types it holds. Unmount handlers are not run.
+///
+/// Returns the number of values removed.
+pub fn remove_all_state_for_id(id: topo::Id) -> usize {
+    STORE.with(|store_refcell| {
+        let mut store_mut = store_refcell.borrow_mut();
+        let removed = store_mut.remove_all_state_with_topo_id(id);
+        store_mut.prune_id_nodes();
+        removed
+    })
+}
+
 /// Provides mutable access to the stored state type T.
 ///
 /// Example:
@@ -123,9 +351,12 @@ pub fn read_state_with_topo_id<T: 'static, F: FnOnce(&T) -> R, R>(id: topo::Id,
 /// Rudamentary Garbage Collection
 /// purges all unseen ids' state
 /// then resets the suneen ids list.
+///
+/// This marks the end of a frame, so it also advances the current frame.
 pub fn purge_and_reset_unseen_ids() {
     purge_unseen_ids();
     reset_unseen_id_list();
+    STORE.with(|store_refcell| store_refcell.borrow_mut().frame += 1);
 }
 
 /// Rudamentary Garbage Collection
@@ -139,6 +370,7 @@ pub fn reset_unseen_id_list() {
         let mut store_mut = store_refcell.borrow_mut();
 
         store_mut.unseen_ids = HashSet::new();
+        store_mut.planned_purge = None;
         let ids = store_mut.id_to_key_map.keys().cloned().collect::<Vec<_>>();
         for id in ids {
             store_mut.unseen_ids.insert(id);
@@ -148,21 +380,139 @@ pub fn reset_unseen_id_list() {
 
 /// Rudamentary Garbage Collection
 ///
-/// Purges all state keyed to ids remaining in unseed ids list
+/// Purges all state keyed to ids remaining in unseed ids list,
+/// subject to the gc policy.
 fn purge_unseen_ids() {
     STORE.with(|store_refcell| {
         let mut store_mut = store_refcell.borrow_mut();
-        let ids = store_mut.unseen_ids.iter().cloned().collect::<Vec<_>>();
+        let ids = store_mut.ids_to_purge();
+        store_mut.planned_purge = None;
 
         for id in ids {
-            let key = store_mut.id_to_key_map.remove(&id);
-            if let Some(key) = key {
-                store_mut.primary_slotmap.remove(key);
-            }
+            store_mut.remove_all_state_with_topo_id(id);
         }
+        store_mut.count_unseen_frames();
+        store_mut.prune_id_nodes();
+        store_mut.prune_atom_subscribers();
     })
 }
 
+/// Initialises the atom if needed and records the current context as
+/// depending on it.
+pub(crate) fn subscribe_to_atom<A: Atom>() {
+    atom_cell::<A>();
+    subscribe_to_atoms(&[TypeId::of::<A>()]);
+}
+
+/// Records the current context as depending on each of the atoms.
+pub(crate) fn subscribe_to_atoms(atoms: &[TypeId]) {
+    let id = topo::Id::current();
+    STORE.with(|store_refcell| {
+        let mut store_mut = store_refcell.borrow_mut();
+        for atom in atoms {
+            store_mut.subscribe_to_atom(*atom, id);
+        }
+    });
+}
+
+// The cell holding the atom's value, initialising the atom if needed.
+fn atom_cell<A: Atom>() -> Rc<RefCell<A::Value>> {
+    let atom = TypeId::of::<A>();
+    let cell = STORE.with(|store_refcell| store_refcell.borrow().atom_cell(atom));
+    cell.unwrap_or_else(|| {
+        // init runs outside the store borrow, it may read other atoms
+        let value = A::init();
+        STORE.with(|store_refcell| store_refcell.borrow_mut().insert_atom(atom, value))
+    })
+}
+
+/// Reads the atom in place, reads nested in other reads are fine.
+///
+/// Panics if the atom is read from within its own update.
+pub(crate) fn read_atom<A: Atom, F: FnOnce(&A::Value) -> R, R>(func: F) -> R {
+    let cell = atom_cell::<A>();
+    let value = cell.try_borrow().unwrap_or_else(|_| {
+        panic!(
+            "Atom {} is read while it is being updated",
+            std::any::type_name::<A>()
+        )
+    });
+    func(&value)
+}
+
+/// Updates the atom, drops the selectors computed from it and marks every
+/// context that has read it as dirty.
+///
+/// Panics if the atom is accessed from within func, or updated while being read.
+pub(crate) fn update_atom<A: Atom, F: FnOnce(&mut A::Value)>(func: F) {
+    let cell = atom_cell::<A>();
+    {
+        let mut value = cell.try_borrow_mut().unwrap_or_else(|_| {
+            panic!(
+                "Atom {} is updated while it is being read or updated",
+                std::any::type_name::<A>()
+            )
+        });
+        func(&mut value);
+    }
+    STORE.with(|store_refcell| {
+        let mut store_mut = store_refcell.borrow_mut();
+        store_mut.invalidate_selectors(TypeId::of::<A>());
+        store_mut.mark_atom_subscribers_dirty(TypeId::of::<A>());
+    });
+}
+
+pub(crate) fn cached_selector<T: 'static + Clone>(selector: usize) -> Option<(T, HashSet<TypeId>)> {
+    STORE.with(|store_refcell| store_refcell.borrow().cached_selector(selector))
+}
+
+pub(crate) fn cache_selector<T: 'static>(selector: usize, value: T, dependencies: HashSet<TypeId>) {
+    STORE.with(|store_refcell| {
+        store_refcell
+            .borrow_mut()
+            .cache_selector(selector, value, dependencies)
+    });
+}
+
+/// Returns the ids of the contexts that read an atom which has since changed,
+/// and clears the list. These are the components that need re-rendering.
+pub fn take_dirty_ids() -> Vec<topo::Id> {
+    STORE.with(|store_refcell| {
+        store_refcell
+            .borrow_mut()
+            .dirty_ids
+            .drain()
+            .collect::<Vec<_>>()
+    })
+}
+
+/// Sets the policy used by purge_and_reset_unseen_ids to decide which unseen
+/// ids to purge.
+pub fn set_gc_policy(gc_policy: GcPolicy) {
+    STORE.with(|store_refcell| store_refcell.borrow_mut().gc_policy = gc_policy)
+}
+
+pub fn gc_policy() -> GcPolicy {
+    STORE.with(|store_refcell| store_refcell.borrow().gc_policy)
+}
+
+/// Exempts, or stops exempting, the state of an id from garbage collection.
+pub fn set_keep_alive(id: topo::Id, keep_alive: bool) {
+    STORE.with(|store_refcell| {
+        let mut store_mut = store_refcell.borrow_mut();
+        if keep_alive {
+            store_mut.keep_alive_ids.insert(id);
+        } else {
+            store_mut.keep_alive_ids.remove(&id);
+        }
+    })
+}
+
+/// The unseen ids that the next purge will remove under the gc policy.
+pub fn ids_to_purge() -> Vec<topo::Id> {
+    STORE.with(|store_refcell| store_refcell.borrow().ids_to_purge())
+}
+
 pub fn unseen_ids() -> Vec<topo::Id> {
     STORE.with(|store_refcell| {
         let store_mut = store_refcell.borrow_mut();
@@ -175,16 +525,135 @@ pub fn unseen_ids() -> Vec<topo::Id> {
 //     Arc::new(move || get_state_with_topo_id::<T>(current_id))
 // }
 
+/// Executes the unmount handlers of all unseen ids that are about to be purged
+/// under the gc policy, then removes them.
+///
+/// Handlers run children first, then parents, for components rendered through
+/// `#[component]` or `enter_component`. Handlers of the same component run in
+/// reverse order of registration.
+///
+/// Plain `#[topo::nested]` functions are not known to be nested in one another,
+/// topo does not expose a context's parent. Their handlers are only ordered by
+/// when their context first called a hook, later first, which is not children
+/// first when a parent calls a child before its own hooks. Inside a `#[component]`
+/// they count as children of that component.
 pub fn execute_and_remove_unmounts() {
-    for id in unseen_ids() {
-        if state_exists_for_topo_id::<Unmount>(id) {
-            read_state_with_topo_id::<Unmount, _, _>(id, |dt| dt.execute_if_activated());
-            remove_state_with_topo_id::<Unmount>(id);
+    let ids = STORE.with(|store_refcell| {
+        let mut store_mut = store_refcell.borrow_mut();
+        let ids = store_mut.ids_to_purge();
+        store_mut.planned_purge = Some(ids.clone());
+        ids
+    });
+    let mut ids = ids
+        .into_iter()
+        .filter(|id| state_exists_for_topo_id::<Unmount>(*id))
+        .collect::<Vec<_>>();
+    sort_children_first(&mut ids);
+
+    for id in ids {
+        if let Some(mut dt) = remove_state_with_topo_id::<Unmount>(id) {
+            dt.execute_if_activated();
+        }
+    }
+}
+
+fn sort_children_first(ids: &mut [topo::Id]) {
+    STORE.with(|store_refcell| {
+        let store = store_refcell.borrow();
+        ids.sort_by_cached_key(|id| {
+            let parent_order = store
+                .id_nodes
+                .get(id)
+                .and_then(|node| node.parent)
+                .map_or(0, |parent| store.order(parent));
+            std::cmp::Reverse((store.depth(*id), parent_order, store.order(*id)))
+        });
+    })
+}
+
+/// Runs the unmount handlers of a context and all of its descendants, children
+/// first, then removes all of their state straight away.
+pub(crate) fn purge_subtree(id: topo::Id) {
+    let subtree = STORE.with(|store_refcell| store_refcell.borrow().subtree(id));
+
+    let mut unmount_ids = subtree
+        .iter()
+        .copied()
+        .filter(|id| state_exists_for_topo_id::<Unmount>(*id))
+        .collect::<Vec<_>>();
+    sort_children_first(&mut unmount_ids);
+    for unmount_id in unmount_ids {
+        if let Some(mut dt) = remove_state_with_topo_id::<Unmount>(unmount_id) {
+            dt.execute_if_activated();
         }
     }
+
+    STORE.with(|store_refcell| {
+        let mut store_mut = store_refcell.borrow_mut();
+        for id in subtree {
+            store_mut.remove_all_state_with_topo_id(id);
+            store_mut.id_nodes.remove(&id);
+        }
+    })
 }
 
-#[topo::nested]
-pub fn on_unmount<F: Fn() -> () + 'static>(unmount_fn: F) -> StateAccess<Unmount> {
+/// Discards the state of a component and of everything rendered below it,
+/// running their unmount handlers first, children before parents.
+///
+/// The state is re-initialised the next time the component is rendered, e.g. for
+/// a wizard step or a "discard changes" button.
+///
+/// Descendants are found through the component tree, so they have to be rendered
+/// through `#[component]` or `enter_component`. A plain `#[topo::nested]` child
+/// called from a plain `#[topo::nested]` parent is not known to be below it and
+/// keeps its state. See subtree_ids.
+pub fn reset_subtree(id: topo::Id) {
+    purge_subtree(id);
+}
+
+/// The id followed by the ids of all known contexts rendered below it,
+/// parents before children.
+///
+/// A context is known to be below a component when it was rendered inside a
+/// `#[component]` or `enter_component`, including through plain functions called
+/// from it. topo does not expose a context's parent, so contexts nested only
+/// through `#[topo::nested]` functions are not linked, only the hooks a function
+/// calls directly are found below its id.
+pub fn subtree_ids(id: topo::Id) -> Vec<topo::Id> {
+    STORE.with(|store_refcell| store_refcell.borrow().subtree(id))
+}
+
+/// Registers a function to be run when the calling component is unmounted,
+/// i.e. when execute_and_remove_unmounts finds it has not been seen.
+///
+/// The function is FnOnce so it can move resources out. Only the function passed
+/// on the first render is kept. A component can register several unmount functions,
+/// either by calling on_unmount more than once or by pushing onto the returned accessor.
+#[track_caller]
+pub fn on_unmount<F: FnOnce() + 'static>(unmount_fn: F) -> StateAccess<Unmount> {
     use_state(|| Unmount::new(unmount_fn))
 }
+
+/// Runs the passed function the first time the calling component is rendered,
+/// and again if it is rendered after having been unmounted.
+#[track_caller]
+pub fn on_mount<F: FnOnce()>(mount_fn: F) {
+    let mounted = use_state(|| false);
+    if !mounted.get() {
+        // bookkeeping, not a write for the write log
+        set_state_with_topo_id(true, mounted.id);
+        mount_fn();
+    }
+}
+
+/// Runs the passed function every time the calling component is rendered,
+/// except for the first time.
+#[track_caller]
+pub fn on_update<F: FnOnce()>(update_fn: F) {
+    let mounted = use_state(|| false);
+    if mounted.get() {
+        update_fn();
+    } else {
+        set_state_with_topo_id(true, mounted.id);
+    }
+}
@@ -1,16 +1,115 @@
 use anymap::any::Any;
 use slotmap::{DefaultKey, Key, SecondaryMap, SlotMap};
 
+use std::any::TypeId;
+use std::cell::RefCell;
 use std::collections::HashMap;
 pub use std::collections::HashSet;
+use std::panic::Location;
+use std::rc::Rc;
 use topo::*;
 
+/// Where an id sits in the tree of contexts known to the store.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IdNode {
+    pub parent: Option<topo::Id>,
+    /// ids registered later have a higher order
+    pub order: u64,
+    /// set for components rendered through `enter_component`
+    pub name: Option<&'static str>,
+}
+
+// Removes the value keyed by the DefaultKey from one registered secondary map,
+// so that all state of an id can be removed without knowing its types.
+type SecondaryMapRemover = fn(&mut anymap::Map<dyn Any>, DefaultKey) -> bool;
+
+fn remove_from_secondarymap<T: 'static>(
+    anymap: &mut anymap::Map<dyn Any>,
+    key: DefaultKey,
+) -> bool {
+    anymap
+        .get_mut::<SecondaryMap<DefaultKey, T>>()
+        .and_then(|sec_map| sec_map.remove(key))
+        .is_some()
+}
+
+/// The cached value of a selector and the atoms it was computed from.
+#[derive(Debug)]
+pub(crate) struct SelectorCache {
+    value: Box<dyn std::any::Any>,
+    dependencies: HashSet<TypeId>,
+}
+
+/// How a state was written.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteKind {
+    Set,
+    Update,
+    Remove,
+}
+
+/// A single write of a state through its StateAccess, recorded in the write log.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StateWrite {
+    pub id: topo::Id,
+    pub type_name: &'static str,
+    pub kind: WriteKind,
+    /// the code that made the write
+    pub location: &'static Location<'static>,
+    pub frame: u64,
+}
+
+/// Controls which unseen ids are purged by the garbage collection.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct GcPolicy {
+    /// number of frames an id can go unseen before its state is purged,
+    /// 0 purges everything not seen in the last frame
+    pub grace_frames: u64,
+    /// maximum number of live contexts, when exceeded the least recently seen
+    /// ids that were not seen in the last frame are purged
+    pub max_live_contexts: Option<usize>,
+}
+
 #[derive(Debug)]
 pub struct Store {
     pub id_to_key_map: HashMap<topo::Id, DefaultKey>,
     pub primary_slotmap: SlotMap<DefaultKey, Id>,
     pub anymap: anymap::Map<dyn Any>,
     pub unseen_ids: HashSet<topo::Id>,
+    pub id_nodes: HashMap<topo::Id, IdNode>,
+    /// incremented at the end of every frame by purge_and_reset_unseen_ids
+    pub frame: u64,
+    pub gc_policy: GcPolicy,
+    /// how many frames in a row each id had gone unseen at the last purge
+    pub unseen_frames: HashMap<topo::Id, u64>,
+    /// ids that are never garbage collected
+    pub keep_alive_ids: HashSet<topo::Id>,
+    /// components currently being rendered, innermost last
+    pub component_stack: Vec<topo::Id>,
+    /// the purge decided on by execute_and_remove_unmounts, so that the purge at
+    /// the end of the same frame removes exactly the contexts that were unmounted
+    pub(crate) planned_purge: Option<Vec<topo::Id>>,
+    /// atom values keyed by the atom type, these are never garbage collected
+    /// each value sits in its own cell, so an atom can be read while it, or the
+    /// store, is in use elsewhere
+    atoms: HashMap<TypeId, Rc<dyn std::any::Any>>,
+    /// the ids that read each atom, with the frame they last read it in
+    atom_subscribers: HashMap<TypeId, HashMap<topo::Id, u64>>,
+    /// selector caches keyed by the address of the selector's function
+    selectors: HashMap<usize, SelectorCache>,
+    /// where each type of state of an id was last written
+    last_writes: HashMap<topo::Id, HashMap<TypeId, &'static Location<'static>>>,
+    /// every write since the log was last taken, None unless enabled
+    pub write_log: Option<Vec<StateWrite>>,
+    /// ids that read an atom which has changed since take_dirty_ids was last called
+    pub dirty_ids: HashSet<topo::Id>,
+    hook_callsites: HashMap<topo::Id, Vec<&'static Location<'static>>>,
+    next_id_order: u64,
+    /// bumped each time a component starts rendering
+    next_render: u64,
+    /// the render each component was last entered in
+    render_starts: HashMap<topo::Id, u64>,
+    secondarymap_removers: Vec<SecondaryMapRemover>,
 }
 
 impl Store {
@@ -20,9 +119,314 @@ impl Store {
             primary_slotmap: SlotMap::new(),
             anymap: anymap::Map::new(),
             unseen_ids: HashSet::new(),
+            id_nodes: HashMap::new(),
+            frame: 0,
+            gc_policy: GcPolicy::default(),
+            unseen_frames: HashMap::new(),
+            keep_alive_ids: HashSet::new(),
+            component_stack: vec![],
+            planned_purge: None,
+            atoms: HashMap::new(),
+            atom_subscribers: HashMap::new(),
+            selectors: HashMap::new(),
+            last_writes: HashMap::new(),
+            write_log: None,
+            dirty_ids: HashSet::new(),
+            hook_callsites: HashMap::new(),
+            next_id_order: 0,
+            next_render: 0,
+            render_starts: HashMap::new(),
+            secondarymap_removers: vec![],
+        }
+    }
+
+    /// Records that child was created from within the parent context.
+    /// The first registration of an id wins.
+    ///
+    /// A parent context that is not known yet, for instance a plain `#[topo::nested]`
+    /// function, is attached to the innermost component being rendered.
+    pub(crate) fn register_child(&mut self, parent: topo::Id, child: topo::Id) {
+        if !self.id_nodes.contains_key(&parent) {
+            let component = self.component_stack.last().copied();
+            self.insert_id_node(parent, component.filter(|id| *id != parent));
+        }
+        if !self.id_nodes.contains_key(&child) {
+            self.insert_id_node(child, Some(parent));
+        }
+    }
+
+    fn insert_id_node(&mut self, id: topo::Id, parent: Option<topo::Id>) {
+        let order = self.next_id_order;
+        self.next_id_order += 1;
+        self.id_nodes.insert(
+            id,
+            IdNode {
+                parent,
+                order,
+                name: None,
+            },
+        );
+    }
+
+    pub(crate) fn enter_component(&mut self, id: topo::Id, name: &'static str) {
+        let parent = self
+            .component_stack
+            .last()
+            .copied()
+            .filter(|parent| *parent != id);
+        if !self.id_nodes.contains_key(&id) {
+            self.insert_id_node(id, parent);
+        }
+        let node = self.id_nodes.get_mut(&id).unwrap();
+        if node.parent.is_none() {
+            node.parent = parent;
+        }
+        node.name = Some(name);
+        self.component_stack.push(id);
+        self.hook_callsites.remove(&id);
+        self.next_render += 1;
+        self.render_starts.insert(id, self.next_render);
+    }
+
+    pub(crate) fn exit_component(&mut self, id: topo::Id) {
+        if self.component_stack.last() == Some(&id) {
+            self.component_stack.pop();
+        }
+        self.hook_callsites.remove(&id);
+    }
+
+    /// Checks that a hook called directly in the body of the component being rendered
+    /// is not called more than once from the same place, i.e. from inside a loop.
+    ///
+    /// Returns the name of the component if the check fails.
+    #[cfg(debug_assertions)]
+    pub(crate) fn check_hook_callsite(
+        &mut self,
+        parent: topo::Id,
+        location: &'static Location<'static>,
+    ) -> Result<(), &'static str> {
+        if self.component_stack.last() != Some(&parent) {
+            return Ok(());
+        }
+        let callsites = self.hook_callsites.entry(parent).or_default();
+        if callsites.contains(&location) {
+            Err(self
+                .id_nodes
+                .get(&parent)
+                .and_then(|node| node.name)
+                .unwrap_or("unnamed"))
+        } else {
+            callsites.push(location);
+            Ok(())
+        }
+    }
+
+    /// Identifies the current render of the context, it changes every time the
+    /// component the context belongs to starts rendering again.
+    /// 0 outside of components.
+    pub(crate) fn render_of(&self, id: topo::Id) -> u64 {
+        self.render_starts
+            .get(&id)
+            .or_else(|| {
+                self.component_stack
+                    .last()
+                    .and_then(|component| self.render_starts.get(component))
+            })
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Registration order of an id, ids that are not registered come first.
+    pub(crate) fn order(&self, id: topo::Id) -> u64 {
+        self.id_nodes.get(&id).map_or(0, |node| node.order)
+    }
+
+    /// Number of known ancestors of an id.
+    pub(crate) fn depth(&self, id: topo::Id) -> usize {
+        let mut depth = 0;
+        let mut current = id;
+        while let Some(parent) = self.id_nodes.get(&current).and_then(|node| node.parent) {
+            depth += 1;
+            current = parent;
+            // guard against a malformed tree looping forever
+            if depth > self.id_nodes.len() {
+                break;
+            }
+        }
+        depth
+    }
+
+    /// The id followed by all of its known descendants, parents before children.
+    pub(crate) fn subtree(&self, id: topo::Id) -> Vec<topo::Id> {
+        let mut children = HashMap::<topo::Id, Vec<(u64, topo::Id)>>::new();
+        for (child, node) in &self.id_nodes {
+            if let Some(parent) = node.parent {
+                children
+                    .entry(parent)
+                    .or_default()
+                    .push((node.order, *child));
+            }
+        }
+
+        let mut subtree = vec![];
+        let mut stack = vec![id];
+        while let Some(next) = stack.pop() {
+            // guard against a malformed tree looping forever
+            if subtree.contains(&next) {
+                continue;
+            }
+            subtree.push(next);
+            if let Some(mut next_children) = children.remove(&next) {
+                next_children.sort_by_key(|(order, _)| *order);
+                stack.extend(next_children.into_iter().rev().map(|(_, child)| child));
+            }
+        }
+        subtree
+    }
+
+    /// Removes every type of state stored for the id, together with the id itself.
+    /// Returns the number of values removed.
+    pub(crate) fn remove_all_state_with_topo_id(&mut self, id: topo::Id) -> usize {
+        self.unseen_ids.remove(&id);
+        self.unseen_frames.remove(&id);
+        self.keep_alive_ids.remove(&id);
+        self.last_writes.remove(&id);
+        let key = match self.id_to_key_map.remove(&id) {
+            Some(key) => key,
+            None => return 0,
+        };
+        self.primary_slotmap.remove(key);
+        let anymap = &mut self.anymap;
+        self.secondarymap_removers
+            .iter()
+            .filter(|remover| remover(anymap, key))
+            .count()
+    }
+
+    /// Drops tree nodes for ids that no longer hold state and have no children.
+    pub(crate) fn prune_id_nodes(&mut self) {
+        loop {
+            let parents = self
+                .id_nodes
+                .values()
+                .filter_map(|node| node.parent)
+                .collect::<HashSet<_>>();
+            let id_to_key_map = &self.id_to_key_map;
+            let before = self.id_nodes.len();
+            self.id_nodes
+                .retain(|id, _| id_to_key_map.contains_key(id) || parents.contains(id));
+            if self.id_nodes.len() == before {
+                break;
+            }
+        }
+        let id_nodes = &self.id_nodes;
+        self.render_starts.retain(|id, _| id_nodes.contains_key(id));
+    }
+
+    pub(crate) fn atom_cell<T: 'static>(&self, atom: TypeId) -> Option<Rc<RefCell<T>>> {
+        self.atoms
+            .get(&atom)
+            .cloned()
+            .and_then(|cell| cell.downcast::<RefCell<T>>().ok())
+    }
+
+    pub(crate) fn insert_atom<T: 'static>(&mut self, atom: TypeId, value: T) -> Rc<RefCell<T>> {
+        let cell = Rc::new(RefCell::new(value));
+        self.atoms.insert(atom, cell.clone());
+        cell
+    }
+
+    pub(crate) fn subscribe_to_atom(&mut self, atom: TypeId, id: topo::Id) {
+        let frame = self.frame;
+        self.atom_subscribers
+            .entry(atom)
+            .or_default()
+            .insert(id, frame);
+    }
+
+    /// Marks every id that has read the atom as dirty.
+    pub(crate) fn mark_atom_subscribers_dirty(&mut self, atom: TypeId) {
+        if let Some(subscribers) = self.atom_subscribers.get(&atom) {
+            self.dirty_ids.extend(subscribers.keys().copied());
         }
     }
 
+    pub(crate) fn cached_selector<T: 'static + Clone>(
+        &self,
+        selector: usize,
+    ) -> Option<(T, HashSet<TypeId>)> {
+        let cache = self.selectors.get(&selector)?;
+        let value = cache.value.downcast_ref::<T>()?;
+        Some((value.clone(), cache.dependencies.clone()))
+    }
+
+    pub(crate) fn cache_selector<T: 'static>(
+        &mut self,
+        selector: usize,
+        value: T,
+        dependencies: HashSet<TypeId>,
+    ) {
+        self.selectors.insert(
+            selector,
+            SelectorCache {
+                value: Box::new(value),
+                dependencies,
+            },
+        );
+    }
+
+    /// Drops the cached values of all selectors computed from the atom.
+    pub(crate) fn invalidate_selectors(&mut self, atom: TypeId) {
+        self.selectors
+            .retain(|_, cache| !cache.dependencies.contains(&atom));
+    }
+
+    /// Forgets subscribers that have not read their atom within the grace period,
+    /// i.e. that are no longer being rendered.
+    pub(crate) fn prune_atom_subscribers(&mut self) {
+        let frame = self.frame;
+        let grace_frames = self.gc_policy.grace_frames;
+        for subscribers in self.atom_subscribers.values_mut() {
+            subscribers.retain(|_, last_read| frame.saturating_sub(*last_read) <= grace_frames);
+        }
+    }
+
+    pub(crate) fn record_write<T: 'static>(
+        &mut self,
+        id: topo::Id,
+        kind: WriteKind,
+        location: &'static Location<'static>,
+    ) {
+        self.last_writes
+            .entry(id)
+            .or_default()
+            .insert(TypeId::of::<T>(), location);
+        let frame = self.frame;
+        if let Some(write_log) = &mut self.write_log {
+            write_log.push(StateWrite {
+                id,
+                type_name: std::any::type_name::<T>(),
+                kind,
+                location,
+                frame,
+            });
+        }
+    }
+
+    pub(crate) fn last_write_location<T: 'static>(
+        &self,
+        id: topo::Id,
+    ) -> Option<&'static Location<'static>> {
+        self.last_writes
+            .get(&id)
+            .and_then(|locations| locations.get(&TypeId::of::<T>()))
+            .copied()
+    }
+
+    pub(crate) fn key_for_id(&self, id: topo::Id) -> DefaultKey {
+        self.id_to_key_map.get(&id).copied().unwrap_or_default()
+    }
+
     pub(crate) fn state_exists_with_topo_id<T: 'static>(&self, id: topo::Id) -> bool {
         match (self.id_to_key_map.get(&id), self.get_secondarymap::<T>()) {
             (Some(existing_key), Some(existing_secondary_map)) => {
@@ -36,7 +440,7 @@ impl Store {
         &mut self,
         current_id: topo::Id,
     ) -> Option<&T> {
-        self.unseen_ids.remove(&current_id);
+        self.mark_id_as_active(current_id);
         match (
             self.id_to_key_map.get(&current_id),
             self.get_secondarymap::<T>(),
@@ -52,6 +456,71 @@ impl Store {
         self.unseen_ids.remove(&id);
     }
 
+    /// Counts one more unseen frame for every id still unseen at the end of the
+    /// frame, and starts the count again for the others.
+    pub(crate) fn count_unseen_frames(&mut self) {
+        let unseen_ids = &self.unseen_ids;
+        self.unseen_frames.retain(|id, _| unseen_ids.contains(id));
+        for id in unseen_ids {
+            if self.id_to_key_map.contains_key(id) {
+                *self.unseen_frames.entry(*id).or_default() += 1;
+            }
+        }
+    }
+
+    /// The unseen ids whose state should be purged under the gc policy.
+    pub(crate) fn ids_to_purge(&self) -> Vec<topo::Id> {
+        if let Some(planned_purge) = &self.planned_purge {
+            return planned_purge.clone();
+        }
+        let grace_frames = self.gc_policy.grace_frames;
+        // including the frame that is ending
+        let unseen_frames = |id: &topo::Id| self.unseen_frames.get(id).copied().unwrap_or(0) + 1;
+
+        let mut purge = vec![];
+        let mut survivors = vec![];
+        for id in &self.unseen_ids {
+            if self.keep_alive_ids.contains(id) {
+                continue;
+            }
+            if unseen_frames(id) > grace_frames {
+                purge.push(*id);
+            } else {
+                survivors.push(*id);
+            }
+        }
+
+        if let Some(max_live_contexts) = self.gc_policy.max_live_contexts {
+            let mut live = self.id_to_key_map.len() - purge.len();
+            if live > max_live_contexts {
+                // evict whole contexts, so a component never loses only part of its state
+                let mut contexts: HashMap<topo::Id, Vec<topo::Id>> = HashMap::new();
+                for id in survivors {
+                    let context = self
+                        .id_nodes
+                        .get(&id)
+                        .and_then(|node| node.parent)
+                        .unwrap_or(id);
+                    contexts.entry(context).or_default().push(id);
+                }
+                let mut contexts = contexts.into_iter().collect::<Vec<_>>();
+                // least recently seen first, i.e. unseen for the most frames
+                contexts.sort_by_key(|(context, ids)| {
+                    let unseen = ids.iter().map(unseen_frames).min().unwrap_or(0);
+                    (std::cmp::Reverse(unseen), self.order(*context))
+                });
+                for (_, ids) in contexts {
+                    if live <= max_live_contexts {
+                        break;
+                    }
+                    live = live.saturating_sub(ids.len());
+                    purge.extend(ids);
+                }
+            }
+        }
+        purge
+    }
+
     pub(crate) fn remove_state_with_topo_id<T: 'static>(
         &mut self,
         current_id: topo::Id,
@@ -83,7 +552,7 @@ impl Store {
     // }
 
     pub(crate) fn set_state_with_topo_id<T: 'static>(&mut self, data: T, current_id: topo::Id) {
-        self.unseen_ids.remove(&current_id);
+        self.mark_id_as_active(current_id);
 
         //unwrap or default to keep borrow checker happy
         let key = self
@@ -109,6 +578,32 @@ impl Store {
         }
     }
 
+    /// Calls func with every stored T and the id that owns it.
+    /// Does not mark any id as seen.
+    pub(crate) fn for_each_state<T: 'static, F: FnMut(topo::Id, &T)>(&self, mut func: F) {
+        if let Some(sec_map) = self.get_secondarymap::<T>() {
+            for (key, value) in sec_map.iter() {
+                if let Some(id) = self.primary_slotmap.get(key) {
+                    func(*id, value);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn for_each_state_mut<T: 'static, F: FnMut(topo::Id, &mut T)>(
+        &mut self,
+        mut func: F,
+    ) {
+        let primary_slotmap = &self.primary_slotmap;
+        if let Some(sec_map) = self.anymap.get_mut::<SecondaryMap<DefaultKey, T>>() {
+            for (key, value) in sec_map.iter_mut() {
+                if let Some(id) = primary_slotmap.get(key) {
+                    func(*id, value);
+                }
+            }
+        }
+    }
+
     fn get_secondarymap<T: 'static>(&self) -> Option<&SecondaryMap<DefaultKey, T>> {
         self.anymap.get::<SecondaryMap<DefaultKey, T>>()
     }
@@ -119,6 +614,106 @@ impl Store {
 
     pub fn register_secondarymap<T: 'static>(&mut self) {
         let sm: SecondaryMap<DefaultKey, T> = SecondaryMap::new();
-        self.anymap.insert(sm);
+        if self.anymap.insert(sm).is_none() {
+            self.secondarymap_removers
+                .push(remove_from_secondarymap::<T>);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Unmounted = Rc<RefCell<Vec<u32>>>;
+
+    #[topo::nested]
+    fn tab(n: u32, unmounted: Unmounted) -> StateAccess<u32> {
+        let state = use_state(|| n);
+        // reading the state marks it as seen, the purge must still remove it
+        on_unmount(move || unmounted.borrow_mut().push(state.get()));
+        state
+    }
+
+    fn app(tabs: &[u32], unmounted: &Unmounted) -> Vec<StateAccess<u32>> {
+        tabs.iter()
+            .map(|n| topo::call_in_slot(n, || tab(*n, unmounted.clone())))
+            .collect()
+    }
+
+    #[test]
+    fn unseen_state_survives_grace_frames_then_is_purged() {
+        set_gc_policy(GcPolicy {
+            grace_frames: 2,
+            max_live_contexts: None,
+        });
+        let unmounted = Unmounted::default();
+        let mut runtime = Runtime::new();
+        let tab = runtime.run_frame(|| app(&[1], &unmounted))[0];
+        tab.set(10);
+
+        runtime.run_frame(|| app(&[], &unmounted));
+        runtime.run_frame(|| app(&[], &unmounted));
+        assert_eq!(tab.soft_get(), Some(10));
+        assert!(unmounted.borrow().is_empty());
+
+        runtime.run_frame(|| app(&[], &unmounted));
+        assert!(!tab.state_exists());
+        assert_eq!(*unmounted.borrow(), vec![10]);
+    }
+
+    #[test]
+    fn keep_alive_state_is_never_purged() {
+        let unmounted = Unmounted::default();
+        let mut runtime = Runtime::new();
+        let tab = runtime.run_frame(|| app(&[1], &unmounted))[0].keep_alive();
+        tab.set(10);
+
+        for _ in 0..5 {
+            runtime.run_frame(|| app(&[], &unmounted));
+        }
+        assert_eq!(tab.soft_get(), Some(10));
+    }
+
+    #[test]
+    fn max_live_contexts_evicts_least_recently_seen_first() {
+        set_gc_policy(GcPolicy {
+            grace_frames: 10,
+            max_live_contexts: Some(6),
+        });
+        let unmounted = Unmounted::default();
+        let mut runtime = Runtime::new();
+        // each tab holds two states, its value and its unmount handler
+        let tabs = runtime.run_frame(|| app(&[1, 2, 3], &unmounted));
+        runtime.run_frame(|| app(&[2, 3], &unmounted));
+        assert!(tabs.iter().all(|tab| tab.state_exists()));
+
+        // tab 1 has gone unseen for longer than tab 2
+        runtime.run_frame(|| app(&[3, 4], &unmounted));
+        assert!(!tabs[0].state_exists());
+        assert!(tabs[1].state_exists());
+        assert!(tabs[2].state_exists());
+        assert_eq!(*unmounted.borrow(), vec![1]);
+    }
+
+    #[test]
+    fn unmounts_and_purge_act_on_the_same_ids() {
+        set_gc_policy(GcPolicy {
+            grace_frames: 10,
+            max_live_contexts: Some(5),
+        });
+        let unmounted = Unmounted::default();
+        let mut runtime = Runtime::new();
+        let tabs = runtime.run_frame(|| app(&[1, 2, 3], &unmounted));
+        // 8 states are live, evicting two tabs brings that under the cap, but once
+        // their unmount handlers are removed evicting a single tab would do
+        runtime.run_frame(|| app(&[4], &unmounted));
+
+        for (n, tab) in (1..).zip(&tabs) {
+            assert_eq!(unmounted.borrow().contains(&n), !tab.state_exists());
+        }
+        assert_eq!(unmounted.borrow().len(), 2);
     }
 }
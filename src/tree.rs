@@ -0,0 +1,315 @@
+use crate::state_access::{CloneState, StateAccess};
+use crate::state_functions::use_state;
+use slotmap::{new_key_type, DenseSlotMap};
+
+new_key_type! {
+    pub struct TreeKey;
+}
+
+/// Constructs a tree whose initial items are all root nodes.
+#[track_caller]
+pub fn use_tree<T, F>(initial_roots_fn: F) -> TreeControl<T>
+where
+    F: FnOnce() -> Vec<T>,
+    T: Clone,
+{
+    let tree_access = use_state(|| Tree::new(initial_roots_fn()));
+
+    TreeControl::new(tree_access)
+}
+
+#[derive(Clone)]
+pub struct TreeControl<T>
+where
+    T: Clone + 'static,
+{
+    tree_access: StateAccess<Tree<T>>,
+}
+
+impl<T> TreeControl<T>
+where
+    T: Clone + 'static,
+{
+    fn new(tree_access: StateAccess<Tree<T>>) -> TreeControl<T> {
+        TreeControl { tree_access }
+    }
+
+    pub fn get_tree(&self) -> Tree<T> {
+        self.tree_access.get()
+    }
+
+    #[track_caller]
+    pub fn clear(&self) {
+        self.tree_access.update(|tree| {
+            tree.nodes = DenseSlotMap::with_key();
+            tree.roots = vec![];
+        });
+    }
+
+    #[track_caller]
+    pub fn insert_root(&self, idx: usize, item: T) -> TreeKey {
+        let mut tree = self.tree_access.get();
+        let key = tree.nodes.insert(TreeNode::new(item, None));
+        tree.roots.insert(idx, key);
+        self.tree_access.set(tree);
+        key
+    }
+
+    #[track_caller]
+    pub fn push_root(&self, item: T) -> TreeKey {
+        let idx = self.tree_access.get_with(|tree| tree.roots.len());
+        self.insert_root(idx, item)
+    }
+
+    /// inserts an item as the idx'th child of parent.
+    /// Returns None if the parent does not exist.
+    #[track_caller]
+    pub fn insert_child(&self, parent: TreeKey, idx: usize, item: T) -> Option<TreeKey> {
+        let mut tree = self.tree_access.get();
+        if !tree.nodes.contains_key(parent) {
+            return None;
+        }
+        let key = tree.nodes.insert(TreeNode::new(item, Some(parent)));
+        tree.nodes[parent].children.insert(idx, key);
+        self.tree_access.set(tree);
+        Some(key)
+    }
+
+    #[track_caller]
+    pub fn push_child(&self, parent: TreeKey, item: T) -> Option<TreeKey> {
+        let idx = self
+            .tree_access
+            .get_with(|tree| tree.nodes.get(parent).map(|node| node.children.len()))?;
+        self.insert_child(parent, idx, item)
+    }
+
+    /// removes the node and all of its descendants, returning the node's item.
+    #[track_caller]
+    pub fn remove(&self, key: TreeKey) -> Option<T> {
+        let mut tree = self.tree_access.get();
+        if !tree.nodes.contains_key(key) {
+            return None;
+        }
+        tree.detach(key);
+        for descendant in tree.descendants(key) {
+            tree.nodes.remove(descendant);
+        }
+        let obj = tree.nodes.remove(key).map(|node| node.item);
+        self.tree_access.set(tree);
+        obj
+    }
+
+    /// Moves a node, together with its descendants, to be the idx'th child of
+    /// new_parent, or the idx'th root if new_parent is None.
+    ///
+    /// Returns false and leaves the tree untouched if either node does not exist or
+    /// if new_parent is inside the subtree being moved.
+    #[track_caller]
+    pub fn move_subtree(&self, key: TreeKey, new_parent: Option<TreeKey>, idx: usize) -> bool {
+        let mut tree = self.tree_access.get();
+        if !tree.nodes.contains_key(key) {
+            return false;
+        }
+        if let Some(parent) = new_parent {
+            if !tree.nodes.contains_key(parent)
+                || parent == key
+                || tree.descendants(key).contains(&parent)
+            {
+                return false;
+            }
+        }
+
+        tree.detach(key);
+        let siblings = match new_parent {
+            Some(parent) => &mut tree.nodes[parent].children,
+            None => &mut tree.roots,
+        };
+        let idx = idx.min(siblings.len());
+        siblings.insert(idx, key);
+        tree.nodes[key].parent = new_parent;
+        self.tree_access.set(tree);
+        true
+    }
+
+    /// updates the item stored at key in place using the provided function
+    #[track_caller]
+    pub fn update<F: FnOnce(&mut T)>(&self, key: TreeKey, func: F) {
+        self.tree_access.update(|tree| {
+            if let Some(node) = tree.nodes.get_mut(key) {
+                func(&mut node.item);
+            }
+        });
+    }
+
+    #[track_caller]
+    pub fn expand(&self, key: TreeKey) {
+        self.set_expanded(key, |_| true);
+    }
+
+    #[track_caller]
+    pub fn collapse(&self, key: TreeKey) {
+        self.set_expanded(key, |_| false);
+    }
+
+    #[track_caller]
+    pub fn toggle(&self, key: TreeKey) {
+        self.set_expanded(key, |expanded| !expanded);
+    }
+
+    #[track_caller]
+    pub fn expand_all(&self) {
+        self.tree_access.update(|tree| {
+            for node in tree.nodes.values_mut() {
+                node.expanded = true;
+            }
+        });
+    }
+
+    #[track_caller]
+    pub fn collapse_all(&self) {
+        self.tree_access.update(|tree| {
+            for node in tree.nodes.values_mut() {
+                node.expanded = false;
+            }
+        });
+    }
+
+    #[track_caller]
+    fn set_expanded<F: FnOnce(bool) -> bool>(&self, key: TreeKey, func: F) {
+        self.tree_access.update(|tree| {
+            if let Some(node) = tree.nodes.get_mut(key) {
+                node.expanded = func(node.expanded);
+            }
+        });
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct TreeNode<T> {
+    pub item: T,
+    pub parent: Option<TreeKey>,
+    pub children: Vec<TreeKey>,
+    pub expanded: bool,
+}
+
+impl<T> TreeNode<T> {
+    fn new(item: T, parent: Option<TreeKey>) -> TreeNode<T> {
+        TreeNode {
+            item,
+            parent,
+            children: vec![],
+            expanded: false,
+        }
+    }
+}
+
+/// A row of the flattened, visible part of a tree.
+pub struct TreeRow<'a, T> {
+    pub key: TreeKey,
+    pub depth: usize,
+    pub item: &'a T,
+    pub has_children: bool,
+    pub expanded: bool,
+}
+
+#[derive(Clone)]
+pub struct Tree<T>
+where
+    T: Clone + 'static,
+{
+    pub nodes: DenseSlotMap<TreeKey, TreeNode<T>>,
+    pub roots: Vec<TreeKey>,
+}
+
+impl<T> PartialEq for Tree<T>
+where
+    T: Clone + PartialEq,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.roots == other.roots
+            && self.nodes.len() == other.nodes.len()
+            && self
+                .nodes
+                .iter()
+                .all(|(key, node)| other.nodes.get(key) == Some(node))
+    }
+}
+
+impl<T> Tree<T>
+where
+    T: Clone + 'static,
+{
+    fn new(mut items: Vec<T>) -> Tree<T> {
+        let mut nodes = DenseSlotMap::with_key();
+        let roots = items
+            .drain(..)
+            .map(|item| nodes.insert(TreeNode::new(item, None)))
+            .collect();
+        Tree { nodes, roots }
+    }
+
+    pub fn get(&self, key: TreeKey) -> Option<&T> {
+        self.nodes.get(key).map(|node| &node.item)
+    }
+
+    pub fn parent(&self, key: TreeKey) -> Option<TreeKey> {
+        self.nodes.get(key).and_then(|node| node.parent)
+    }
+
+    pub fn children(&self, key: TreeKey) -> &[TreeKey] {
+        self.nodes
+            .get(key)
+            .map(|node| node.children.as_slice())
+            .unwrap_or(&[])
+    }
+
+    pub fn is_expanded(&self, key: TreeKey) -> bool {
+        self.nodes.get(key).is_some_and(|node| node.expanded)
+    }
+
+    /// all descendants of key, depth first, not including key itself
+    pub fn descendants(&self, key: TreeKey) -> Vec<TreeKey> {
+        let mut descendants = vec![];
+        let mut stack = self.children(key).iter().rev().copied().collect::<Vec<_>>();
+        while let Some(next) = stack.pop() {
+            descendants.push(next);
+            stack.extend(self.children(next).iter().rev());
+        }
+        descendants
+    }
+
+    /// an iterator over the rows that are visible, i.e. every root and every node
+    /// whose ancestors are all expanded, in display order.
+    pub fn visible_rows(&self) -> impl Iterator<Item = TreeRow<'_, T>> {
+        let mut rows = vec![];
+        let mut stack = self
+            .roots
+            .iter()
+            .rev()
+            .map(|key| (*key, 0))
+            .collect::<Vec<_>>();
+        while let Some((key, depth)) = stack.pop() {
+            if let Some(node) = self.nodes.get(key) {
+                rows.push(TreeRow {
+                    key,
+                    depth,
+                    item: &node.item,
+                    has_children: !node.children.is_empty(),
+                    expanded: node.expanded,
+                });
+                if node.expanded {
+                    stack.extend(node.children.iter().rev().map(|child| (*child, depth + 1)));
+                }
+            }
+        }
+        rows.into_iter()
+    }
+
+    // unlinks key from its parent's children or from the roots
+    fn detach(&mut self, key: TreeKey) {
+        match self.nodes[key].parent {
+            Some(parent) => self.nodes[parent].children.retain(|k| *k != key),
+            None => self.roots.retain(|k| *k != key),
+        }
+    }
+}
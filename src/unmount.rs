@@ -1,21 +1,33 @@
 use crate::StateAccess;
 
+/// The cleanups to run when a context is unmounted.
+///
+/// Cleanups are FnOnce so they can move resources out, for instance to drop a
+/// channel sender or join a thread handle. They run in reverse order of
+/// registration and each runs at most once.
 pub struct Unmount {
     pub activated: bool,
-    pub on_unmount: Box<dyn Fn() -> ()>,
+    pub on_unmount: Vec<Box<dyn FnOnce()>>,
 }
 
 impl Unmount {
-    pub fn new(on_unmount: impl Fn() -> () + 'static) -> Self {
+    pub fn new(on_unmount: impl FnOnce() + 'static) -> Self {
         Self {
             activated: true,
-            on_unmount: Box::new(on_unmount),
+            on_unmount: vec![Box::new(on_unmount)],
         }
     }
 
-    pub fn execute_if_activated(&self) {
+    /// adds another cleanup to this context
+    pub fn push(&mut self, on_unmount: impl FnOnce() + 'static) {
+        self.on_unmount.push(Box::new(on_unmount));
+    }
+
+    pub fn execute_if_activated(&mut self) {
         if self.activated {
-            (self.on_unmount)();
+            while let Some(on_unmount) = self.on_unmount.pop() {
+                on_unmount();
+            }
         }
     }
 
@@ -30,15 +42,15 @@ impl Unmount {
 pub trait StateAccessUnmount {
     fn activate(&self);
     fn deactivate(&self);
+    fn push(&self, on_unmount: impl FnOnce() + 'static);
     fn execute_and_remove(self);
 }
 
 impl StateAccessUnmount for StateAccess<Unmount> {
     fn execute_and_remove(self) {
-        self.update(|dt| {
+        if let Some(mut dt) = self.remove() {
             dt.execute_if_activated();
-        });
-        self.remove();
+        }
     }
 
     fn activate(&self) {
@@ -48,4 +60,8 @@ impl StateAccessUnmount for StateAccess<Unmount> {
     fn deactivate(&self) {
         self.update(|dt| dt.deactivate());
     }
+
+    fn push(&self, on_unmount: impl FnOnce() + 'static) {
+        self.update(|dt| dt.push(on_unmount));
+    }
 }